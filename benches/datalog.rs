@@ -0,0 +1,47 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use datalog::datalog::Program;
+
+const NUM_NODES: usize = 200;
+
+// Transitive closure over a chain, which is mostly joining and projecting
+// narrow rows: every rule body builds a row out of two others.
+fn benchmark_reachable(c: &mut Criterion) {
+    let mut src = String::new();
+    for i in 0..NUM_NODES {
+        src.push_str(&format!("edge({}, {}).\n", i, i + 1));
+    }
+    src.push_str("reachable(X, Y) <- edge(X, Y).\n");
+    src.push_str("reachable(X, Z) <- reachable(X, Y), edge(Y, Z).\n");
+    let p = Program::build(&src).unwrap();
+
+    c.bench_function("datalog/reachable", |b| {
+        b.iter(|| black_box(p.clone().render("reachable").unwrap()))
+    });
+}
+
+// The same over atoms rather than integers, with wider rows.
+fn benchmark_reachable_atoms(c: &mut Criterion) {
+    let mut src = String::new();
+    for i in 0..NUM_NODES {
+        src.push_str(&format!(
+            "edge(\"node {}\", \"node {}\", \"label {}\").\n",
+            i,
+            i + 1,
+            i % 7
+        ));
+    }
+    src.push_str("path(X, Y, L, L) <- edge(X, Y, L).\n");
+    src.push_str("path(X, Z, F, L) <- path(X, Y, F, P), edge(Y, Z, L).\n");
+    let p = Program::build(&src).unwrap();
+
+    c.bench_function("datalog/reachable_atoms", |b| {
+        b.iter(|| black_box(p.clone().render("path").unwrap()))
+    });
+}
+
+criterion_group!(
+    datalog_benches,
+    benchmark_reachable,
+    benchmark_reachable_atoms,
+);
+criterion_main!(datalog_benches);
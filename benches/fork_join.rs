@@ -36,6 +36,32 @@ fn benchmark_babyflow(c: &mut Criterion) {
     });
 }
 
+fn benchmark_babyflow_parallel(c: &mut Criterion) {
+    use datalog::babyflow::parallel::Query;
+
+    c.bench_function("babyflow parallel", |b| {
+        b.iter(|| {
+            let mut q = Query::new();
+
+            let mut op = q.source(move |send| {
+                send.give_iterator(0..NUM_INTS);
+            });
+
+            for _ in 0..NUM_OPS {
+                op = q.concat(
+                    (0..BRANCH_FACTOR).map(|i| op.clone().filter(move |x| x % BRANCH_FACTOR == i)),
+                );
+            }
+
+            op.sink(|i| {
+                black_box(i);
+            });
+
+            q.run(4);
+        })
+    });
+}
+
 fn benchmark_timely(c: &mut Criterion) {
     c.bench_function("timely", |b| {
         b.iter(|| {
@@ -166,6 +192,7 @@ fn benchmark_spinach_switch(c: &mut Criterion) {
 criterion_group!(
     fork_join_dataflow,
     benchmark_babyflow,
+    benchmark_babyflow_parallel,
     benchmark_timely,
     benchmark_spinach,
     benchmark_spinach_switch,
@@ -0,0 +1,370 @@
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+};
+
+use crate::babyflow::{Operator, RecvCtx, SendCtx};
+
+pub type Diff = isize;
+
+// A stream of changes to a multiset: each record is added to the collection
+// `diff` times, and a negative diff retracts it. Unlike a plain Operator, the
+// stateful operators here correctly respond to retractions.
+#[derive(Clone)]
+pub struct Collection<T>
+where
+    T: Clone,
+{
+    inner: Operator<(T, Diff)>,
+}
+
+impl<T> Operator<(T, Diff)>
+where
+    T: Clone,
+{
+    pub fn as_collection(self) -> Collection<T> {
+        Collection { inner: self }
+    }
+}
+
+impl<T> Operator<T>
+where
+    T: Clone + 'static,
+{
+    // Treats every record in the stream as an insertion.
+    pub fn to_collection(self) -> Collection<T> {
+        self.map(|x| (x, 1)).as_collection()
+    }
+}
+
+// Sums up the diffs for each record, dropping the ones that cancel out.
+fn consolidate<T>(updates: impl IntoIterator<Item = (T, Diff)>) -> Vec<(T, Diff)>
+where
+    T: Eq + Hash,
+{
+    let mut counts: HashMap<T, Diff> = HashMap::new();
+    for (t, d) in updates {
+        *counts.entry(t).or_default() += d;
+    }
+    counts.into_iter().filter(|(_, d)| *d != 0).collect()
+}
+
+fn update<K, V>(tab: &mut HashMap<K, HashMap<V, Diff>>, k: K, v: V, d: Diff)
+where
+    K: Eq + Hash,
+    V: Eq + Hash,
+{
+    let vals = tab.entry(k).or_default();
+    let count = vals.entry(v).or_default();
+    *count += d;
+    if *count == 0 {
+        vals.retain(|_, d| *d != 0);
+    }
+}
+
+impl<T> Collection<T>
+where
+    T: Clone + 'static,
+{
+    pub fn inner(self) -> Operator<(T, Diff)> {
+        self.inner
+    }
+
+    pub fn map<U, F>(self, f: F) -> Collection<U>
+    where
+        F: Fn(T) -> U + 'static,
+        U: Clone + 'static,
+    {
+        self.inner.map(move |(t, d)| (f(t), d)).as_collection()
+    }
+
+    pub fn filter<F>(self, f: F) -> Collection<T>
+    where
+        F: Fn(&T) -> bool + 'static,
+    {
+        self.inner.filter(move |(t, _)| f(t)).as_collection()
+    }
+
+    pub fn negate(self) -> Collection<T> {
+        self.inner.map(|(t, d)| (t, -d)).as_collection()
+    }
+
+    pub fn concat(self, rhs: Collection<T>) -> Collection<T> {
+        self.inner.union(rhs.inner).as_collection()
+    }
+
+    pub fn consolidate(self) -> Collection<T>
+    where
+        T: Eq + Hash,
+    {
+        self.inner
+            .unary(|recv: &RecvCtx<(T, Diff)>, send| {
+                let updates = consolidate(recv.take_all());
+                if !updates.is_empty() {
+                    send.give_iterator(updates);
+                }
+            })
+            .as_collection()
+    }
+
+    // Each record with a positive count appears once in the output.
+    pub fn distinct(self) -> Collection<T>
+    where
+        T: Eq + Hash,
+    {
+        let mut counts: HashMap<T, Diff> = HashMap::new();
+        self.inner
+            .unary(move |recv: &RecvCtx<(T, Diff)>, send| {
+                for (t, d) in consolidate(recv.take_all()) {
+                    let old = counts.get(&t).cloned().unwrap_or(0);
+                    let new = old + d;
+                    if old <= 0 && new > 0 {
+                        send.push((t.clone(), 1));
+                    } else if old > 0 && new <= 0 {
+                        send.push((t.clone(), -1));
+                    }
+                    if new == 0 {
+                        counts.remove(&t);
+                    } else {
+                        counts.insert(t, new);
+                    }
+                }
+            })
+            .as_collection()
+    }
+
+    pub fn sink<F>(self, f: F)
+    where
+        F: Fn(T, Diff) + 'static,
+    {
+        self.inner.sink(move |(t, d)| f(t, d))
+    }
+}
+
+impl<K, V> Collection<(K, V)>
+where
+    K: Eq + Hash + Clone + 'static,
+    V: Eq + Hash + Clone + 'static,
+{
+    pub fn join<V2>(self, rhs: Collection<(K, V2)>) -> Collection<(K, V, V2)>
+    where
+        V2: Eq + Hash + Clone + 'static,
+    {
+        let mut left_tab: HashMap<K, HashMap<V, Diff>> = HashMap::new();
+        let mut right_tab: HashMap<K, HashMap<V2, Diff>> = HashMap::new();
+
+        self.inner
+            .binary(
+                rhs.inner,
+                move |left: &RecvCtx<((K, V), Diff)>,
+                      right: &RecvCtx<((K, V2), Diff)>,
+                      send: &SendCtx<((K, V, V2), Diff)>| {
+                    // Each side's changes are matched against everything the
+                    // other side has accumulated so far, so every pair is
+                    // produced exactly once.
+                    for ((k, v), d) in left.take_all() {
+                        if let Some(matches) = right_tab.get(&k) {
+                            for (v2, d2) in matches {
+                                send.push(((k.clone(), v.clone(), v2.clone()), d * d2));
+                            }
+                        }
+                        update(&mut left_tab, k, v, d);
+                    }
+
+                    for ((k, v2), d2) in right.take_all() {
+                        if let Some(matches) = left_tab.get(&k) {
+                            for (v, d) in matches {
+                                send.push(((k.clone(), v.clone(), v2.clone()), d * d2));
+                            }
+                        }
+                        update(&mut right_tab, k, v2, d2);
+                    }
+                },
+            )
+            .as_collection()
+    }
+
+    // The records whose key isn't in rhs. rhs can change at any point: a key
+    // showing up retracts the records with it, and its last copy going away
+    // puts them back.
+    pub fn antijoin(self, rhs: Collection<K>) -> Collection<(K, V)> {
+        let mut left_tab: HashMap<K, HashMap<V, Diff>> = HashMap::new();
+        let mut right_counts: HashMap<K, Diff> = HashMap::new();
+
+        self.inner
+            .binary(
+                rhs.inner,
+                move |left: &RecvCtx<((K, V), Diff)>,
+                      right: &RecvCtx<(K, Diff)>,
+                      send: &SendCtx<((K, V), Diff)>| {
+                    for (k, d) in consolidate(right.take_all()) {
+                        let old = right_counts.get(&k).cloned().unwrap_or(0);
+                        let new = old + d;
+                        let sign = if old <= 0 && new > 0 {
+                            -1
+                        } else if old > 0 && new <= 0 {
+                            1
+                        } else {
+                            0
+                        };
+                        if sign != 0 {
+                            for (v, d) in left_tab.get(&k).into_iter().flatten() {
+                                send.push(((k.clone(), v.clone()), sign * d));
+                            }
+                        }
+                        if new == 0 {
+                            right_counts.remove(&k);
+                        } else {
+                            right_counts.insert(k, new);
+                        }
+                    }
+
+                    for ((k, v), d) in left.take_all() {
+                        if right_counts.get(&k).is_none_or(|c| *c <= 0) {
+                            send.push(((k.clone(), v.clone()), d));
+                        }
+                        update(&mut left_tab, k, v, d);
+                    }
+                },
+            )
+            .as_collection()
+    }
+
+    // Groups the collection by key, and maintains the output of `f` for each
+    // group. `f` is given the values in the group with their (positive)
+    // counts, and returns the group's output with their counts. Whenever a
+    // group changes its old output is retracted and its new one inserted.
+    pub fn reduce<U, F>(self, f: F) -> Collection<(K, U)>
+    where
+        U: Eq + Hash + Clone + 'static,
+        F: Fn(&K, &[(V, Diff)]) -> Vec<(U, Diff)> + 'static,
+    {
+        let mut groups: HashMap<K, HashMap<V, Diff>> = HashMap::new();
+        let mut outputs: HashMap<K, Vec<(U, Diff)>> = HashMap::new();
+
+        self.inner
+            .unary(move |recv: &RecvCtx<((K, V), Diff)>, send| {
+                let mut changed = HashSet::new();
+                for ((k, v), d) in recv.take_all() {
+                    changed.insert(k.clone());
+                    update(&mut groups, k, v, d);
+                }
+
+                let mut updates = Vec::new();
+                for k in changed {
+                    let input: Vec<_> = groups
+                        .get(&k)
+                        .into_iter()
+                        .flatten()
+                        .filter(|(_, d)| **d > 0)
+                        .map(|(v, d)| (v.clone(), *d))
+                        .collect();
+                    if input.is_empty() {
+                        groups.remove(&k);
+                    }
+                    let new = if input.is_empty() {
+                        Vec::new()
+                    } else {
+                        f(&k, &input)
+                    };
+                    let old = outputs.remove(&k).unwrap_or_default();
+
+                    updates.extend(old.into_iter().map(|(u, d)| ((k.clone(), u), -d)));
+                    updates.extend(new.iter().map(|(u, d)| ((k.clone(), u.clone()), *d)));
+                    if !new.is_empty() {
+                        outputs.insert(k, new);
+                    }
+                }
+
+                let updates = consolidate(updates);
+                if !updates.is_empty() {
+                    send.give_iterator(updates);
+                }
+            })
+            .as_collection()
+    }
+}
+
+#[test]
+fn test_collection() {
+    use crate::babyflow::Query;
+    use std::{cell::RefCell, rc::Rc};
+
+    // Accumulates the changes to a collection into its current contents.
+    fn contents<T>(c: Collection<T>) -> Rc<RefCell<HashMap<T, Diff>>>
+    where
+        T: Eq + Hash + Clone + 'static,
+    {
+        let out = Rc::new(RefCell::new(HashMap::new()));
+        let moved = out.clone();
+        c.sink(move |t, d| {
+            let mut out = moved.borrow_mut();
+            *out.entry(t).or_default() += d;
+            out.retain(|_, d| *d != 0);
+        });
+        out
+    }
+
+    fn sorted<T: Ord + Clone>(m: &Rc<RefCell<HashMap<T, Diff>>>) -> Vec<(T, Diff)> {
+        let mut v: Vec<_> = m.borrow().iter().map(|(t, d)| (t.clone(), *d)).collect();
+        v.sort();
+        v
+    }
+
+    let mut q = Query::new();
+    let (edges, edge_op) = q.input();
+    let edge_op = edge_op.as_collection();
+
+    let degrees = contents(
+        edge_op
+            .clone()
+            .reduce(|_, vals: &[(i64, Diff)]| vec![(vals.len(), 1)]),
+    );
+
+    // Edges out of nodes that aren't blocked.
+    let (blocked, blocked_op) = q.input();
+    let open = contents(edge_op.clone().antijoin(blocked_op.as_collection()));
+
+    // Pairs of nodes connected by a path of length two.
+    let paths = contents(
+        edge_op
+            .clone()
+            .map(|(a, b)| (b, a))
+            .join(edge_op)
+            .map(|(_, a, c)| (a, c))
+            .distinct(),
+    );
+
+    edges.give_vec(&mut vec![
+        ((1, 2), 1),
+        ((1, 3), 1),
+        ((2, 3), 1),
+        ((3, 4), 1),
+    ]);
+    (*q.df).borrow_mut().run();
+    assert_eq!(sorted(&paths), vec![((1, 3), 1), ((1, 4), 1), ((2, 4), 1)]);
+    assert_eq!(
+        sorted(&degrees),
+        vec![((1, 2), 1), ((2, 1), 1), ((3, 1), 1)]
+    );
+
+    // Retracting edges retracts the paths which only existed through them,
+    // and updates the degrees of their sources.
+    edges.push(((3, 4), -1));
+    edges.push(((1, 2), -1));
+    (*q.df).borrow_mut().run();
+    assert_eq!(sorted(&paths), vec![]);
+    assert_eq!(sorted(&degrees), vec![((1, 1), 1), ((2, 1), 1)]);
+    assert_eq!(sorted(&open), vec![((1, 3), 1), ((2, 3), 1)]);
+
+    // Blocking a node takes its edges out, and unblocking it puts them back.
+    blocked.give_vec(&mut vec![(1, 1), (1, 1)]);
+    (*q.df).borrow_mut().run();
+    assert_eq!(sorted(&open), vec![((2, 3), 1)]);
+    blocked.push((1, -1));
+    (*q.df).borrow_mut().run();
+    assert_eq!(sorted(&open), vec![((2, 3), 1)]);
+    blocked.push((1, -1));
+    (*q.df).borrow_mut().run();
+    assert_eq!(sorted(&open), vec![((1, 3), 1), ((2, 3), 1)]);
+}
@@ -4,9 +4,20 @@ use std::{
     rc::Rc,
 };
 
+mod collection;
+pub mod parallel;
+mod progress;
 mod query;
+mod spill;
+mod worker;
 
-pub use query::{Operator, Query};
+pub use collection::{Collection, Diff};
+pub use progress::{Event, Probe, Stream, Time, TimedInput};
+pub use query::{Arranged, Operator, Query};
+use spill::Spiller;
+pub use spill::{Spill, SpillStats};
+pub use worker::execute;
+use worker::Worker;
 
 // TODO: make this work without clone.
 #[derive(Debug, Clone)]
@@ -49,6 +60,10 @@ pub struct Dataflow {
     dirties: Vec<Vec<Rc<RefCell<bool>>>>,
     schedule: Rc<RefCell<Schedule<usize>>>,
     adjacencies: Vec<Vec<usize>>,
+    worker: Option<Worker>,
+    // Where stateful operators put state that doesn't fit in memory, if
+    // there's a limit on it.
+    spiller: Option<Rc<Spiller>>,
 }
 
 pub struct RecvCtx<T> {
@@ -126,6 +141,34 @@ where
     }
 }
 
+// A handle for feeding data into a running Dataflow from the outside. Data
+// given to it is picked up by the next call to Dataflow::run.
+pub struct InputHandle<T> {
+    id: usize,
+    data: Rc<RefCell<Vec<T>>>,
+    schedule: Rc<RefCell<Schedule<usize>>>,
+}
+
+impl<T> InputHandle<T> {
+    pub fn push(&self, t: T) {
+        (*self.data).borrow_mut().push(t);
+        (*self.schedule).borrow_mut().insert(self.id);
+    }
+
+    pub fn give_vec(&self, v: &mut Vec<T>) {
+        (*self.data).borrow_mut().append(v);
+        (*self.schedule).borrow_mut().insert(self.id);
+    }
+
+    pub fn give_iterator<I>(&self, v: I)
+    where
+        I: IntoIterator<Item = T>,
+    {
+        (*self.data).borrow_mut().extend(v);
+        (*self.schedule).borrow_mut().insert(self.id);
+    }
+}
+
 #[derive(Clone)]
 pub struct InputPort<T> {
     id: usize,
@@ -152,6 +195,8 @@ impl Dataflow {
             dirties: Vec::new(),
             adjacencies: Vec::new(),
             schedule: Rc::new(RefCell::new(Schedule::new())),
+            worker: None,
+            spiller: None,
         }
     }
 
@@ -176,6 +221,21 @@ impl Dataflow {
         }
     }
 
+    // Runs until this worker is out of work, and then waits for data from the
+    // other workers. Returns false once all of them are finished.
+    fn step(&mut self) -> bool {
+        self.run();
+        match self.worker.as_mut().and_then(|w| w.receive()) {
+            Some(ops) => {
+                for op in ops {
+                    (*self.schedule).borrow_mut().insert(op);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
     pub fn add_edge<T: Clone>(&mut self, o: SendCtx<T>, i: InputPort<T>) {
         (*o.subscribers).borrow_mut().push(i.data.data.clone());
         self.adjacencies[o.id].push(i.id);
@@ -197,6 +257,26 @@ impl Dataflow {
         self.add_op(move |recv, _send: &SendCtx<()>| f(recv)).0
     }
 
+    pub fn add_input<T>(&mut self) -> (InputHandle<T>, SendCtx<T>)
+    where
+        T: Clone + 'static,
+    {
+        let (input, send) = self.add_op(|recv, send| {
+            let mut data = recv.take_all();
+            if !data.is_empty() {
+                send.give_vec(&mut data);
+            }
+        });
+        (
+            InputHandle {
+                id: input.id,
+                data: input.data.data,
+                schedule: self.schedule.clone(),
+            },
+            send,
+        )
+    }
+
     fn make_send_ctx<T>(&mut self, id: usize) -> SendCtx<T>
     where
         T: Clone,
@@ -286,6 +366,38 @@ fn test_df() {
     df.run();
 }
 
+#[test]
+fn test_df_input() {
+    let mut df = Dataflow::new();
+
+    let runs = Rc::new(RefCell::new(0));
+    let r = runs.clone();
+    let source = df.add_source(move |ctx: &SendCtx<i64>| {
+        *(*r).borrow_mut() += 1;
+        ctx.push(0);
+    });
+    let (handle, input) = df.add_input();
+
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let s = seen.clone();
+    let (i1, i2, _) = df.add_op_2(move |r1, r2, _: &SendCtx<()>| {
+        (*s).borrow_mut().extend(r1.take_all());
+        (*s).borrow_mut().extend(r2.take_all());
+    });
+    df.add_edge(source, i1);
+    df.add_edge(input, i2);
+
+    handle.push(1);
+    df.run();
+    assert_eq!(*(*seen).borrow(), vec![0, 1]);
+
+    // Only the new data flows through, and the source isn't run again.
+    handle.give_vec(&mut vec![2, 3]);
+    df.run();
+    assert_eq!(*(*seen).borrow(), vec![0, 1, 2, 3]);
+    assert_eq!(*(*runs).borrow(), 1);
+}
+
 #[test]
 fn test_df_binary() {
     let mut df = Dataflow::new();
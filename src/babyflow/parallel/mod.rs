@@ -0,0 +1,302 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+};
+
+use super::Schedule;
+
+mod query;
+
+pub use query::{Operator, Query};
+
+// A Dataflow whose operators and buffers are Send, so that independent
+// operators can be run concurrently on a pool of worker threads.
+pub struct Dataflow {
+    operators: Vec<Mutex<Box<dyn FnMut() + Send>>>,
+    dirties: Vec<Arc<AtomicBool>>,
+    adjacencies: Vec<Vec<usize>>,
+    state: Mutex<State>,
+    ready: Condvar,
+}
+
+struct State {
+    schedule: Schedule<usize>,
+    // Operators currently being run by some worker, and whether they were
+    // scheduled again while running.
+    running: Vec<bool>,
+    rerun: Vec<bool>,
+    active: usize,
+}
+
+pub struct RecvCtx<T> {
+    inputs: Arc<Mutex<Vec<T>>>,
+}
+
+impl<I> RecvCtx<I> {
+    pub fn pull(&self) -> Option<I> {
+        self.inputs.lock().unwrap().pop()
+    }
+
+    pub fn take_all(&self) -> Vec<I> {
+        std::mem::take(&mut *self.inputs.lock().unwrap())
+    }
+}
+
+type Subscribers<O> = Arc<Mutex<Vec<Arc<Mutex<Vec<O>>>>>>;
+
+#[derive(Clone)]
+pub struct SendCtx<O>
+where
+    O: Clone,
+{
+    id: usize,
+    subscribers: Subscribers<O>,
+    dirty: Arc<AtomicBool>,
+}
+
+impl<O> SendCtx<O>
+where
+    O: Clone,
+{
+    pub fn push(&self, o: O) {
+        for sub in &*self.subscribers.lock().unwrap() {
+            sub.lock().unwrap().push(o.clone())
+        }
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+
+    pub fn give_vec(&self, v: &mut Vec<O>) {
+        let subs = &*self.subscribers.lock().unwrap();
+        if let Some((last, rest)) = subs.split_last() {
+            for sub in rest {
+                sub.lock().unwrap().extend_from_slice(v);
+            }
+            let mut last = last.lock().unwrap();
+            if last.is_empty() {
+                *last = std::mem::take(v);
+            } else {
+                last.append(v);
+            }
+        }
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+
+    pub fn give_iterator<I>(&self, v: I)
+    where
+        I: IntoIterator<Item = O>,
+    {
+        let subs = &*self.subscribers.lock().unwrap();
+        if subs.is_empty() {
+            return;
+        }
+        let mut first = subs[0].lock().unwrap();
+        let l = first.len();
+        first.extend(v);
+        // Now copy that data from the first one over to the rest.
+        for sub in subs.iter().skip(1) {
+            sub.lock().unwrap().extend_from_slice(&first[l..]);
+        }
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+}
+
+#[derive(Clone)]
+pub struct InputPort<T> {
+    id: usize,
+    data: Arc<Mutex<Vec<T>>>,
+}
+
+impl Dataflow {
+    pub fn new() -> Self {
+        Dataflow {
+            operators: Vec::new(),
+            dirties: Vec::new(),
+            adjacencies: Vec::new(),
+            state: Mutex::new(State {
+                schedule: Schedule::new(),
+                running: Vec::new(),
+                rerun: Vec::new(),
+                active: 0,
+            }),
+            ready: Condvar::new(),
+        }
+    }
+
+    // Runs the dataflow to completion using `workers` threads.
+    pub fn run(&mut self, workers: usize) {
+        let this = &*self;
+        thread::scope(|s| {
+            for _ in 0..workers.max(1) {
+                s.spawn(move || this.work());
+            }
+        });
+    }
+
+    fn work(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(id) = state.schedule.pop() {
+                // Operators aren't reentrant, so if someone else is already
+                // running this one, let them pick it up again when they're done.
+                if state.running[id] {
+                    state.rerun[id] = true;
+                    continue;
+                }
+                state.running[id] = true;
+                state.active += 1;
+                drop(state);
+
+                (*self.operators[id].lock().unwrap())();
+                let dirty = self.dirties[id].swap(false, Ordering::SeqCst);
+
+                state = self.state.lock().unwrap();
+                state.running[id] = false;
+                state.active -= 1;
+                if std::mem::replace(&mut state.rerun[id], false) {
+                    state.schedule.insert(id);
+                }
+                if dirty {
+                    for op in &self.adjacencies[id] {
+                        state.schedule.insert(*op);
+                    }
+                }
+                self.ready.notify_all();
+            } else if state.active == 0 {
+                return;
+            } else {
+                state = self.ready.wait(state).unwrap();
+            }
+        }
+    }
+
+    pub fn add_edge<T: Clone>(&mut self, o: SendCtx<T>, i: InputPort<T>) {
+        o.subscribers.lock().unwrap().push(i.data);
+        self.adjacencies[o.id].push(i.id);
+    }
+
+    pub fn add_source<F, O>(&mut self, mut f: F) -> SendCtx<O>
+    where
+        F: FnMut(&SendCtx<O>) + Send + 'static,
+        O: Clone + Send + 'static,
+    {
+        self.add_op(move |_recv: &RecvCtx<()>, send| f(send)).1
+    }
+
+    pub fn add_sink<F, I>(&mut self, mut f: F) -> InputPort<I>
+    where
+        F: FnMut(&RecvCtx<I>) + Send + 'static,
+        I: Clone + Send + 'static,
+    {
+        self.add_op(move |recv, _send: &SendCtx<()>| f(recv)).0
+    }
+
+    fn make_send_ctx<T>(&mut self, id: usize) -> SendCtx<T>
+    where
+        T: Clone,
+    {
+        SendCtx {
+            id,
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+            dirty: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    fn push_op(&mut self, op: Box<dyn FnMut() + Send>, dirty: Arc<AtomicBool>) -> usize {
+        let id = self.operators.len();
+        self.operators.push(Mutex::new(op));
+        self.dirties.push(dirty);
+        self.adjacencies.push(Vec::new());
+
+        let state = self.state.get_mut().unwrap();
+        state.running.push(false);
+        state.rerun.push(false);
+        state.schedule.insert(id);
+        id
+    }
+
+    pub fn add_op_2<F, I1, I2, O>(&mut self, mut f: F) -> (InputPort<I1>, InputPort<I2>, SendCtx<O>)
+    where
+        F: FnMut(&RecvCtx<I1>, &RecvCtx<I2>, &SendCtx<O>) + Send + 'static,
+        I1: Send + 'static,
+        I2: Send + 'static,
+        O: Clone + Send + 'static,
+    {
+        let id = self.operators.len();
+        let buf1 = Arc::new(Mutex::new(Vec::new()));
+        let buf2 = Arc::new(Mutex::new(Vec::new()));
+        let recv1 = RecvCtx {
+            inputs: buf1.clone(),
+        };
+        let recv2 = RecvCtx {
+            inputs: buf2.clone(),
+        };
+
+        let send = self.make_send_ctx(id);
+        let s = send.clone();
+        self.push_op(Box::new(move || f(&recv1, &recv2, &s)), send.dirty.clone());
+
+        (
+            InputPort { id, data: buf1 },
+            InputPort { id, data: buf2 },
+            send,
+        )
+    }
+
+    pub fn add_op<F, I, O>(&mut self, mut f: F) -> (InputPort<I>, SendCtx<O>)
+    where
+        F: FnMut(&RecvCtx<I>, &SendCtx<O>) + Send + 'static,
+        I: Send + 'static,
+        O: Clone + Send + 'static,
+    {
+        let id = self.operators.len();
+        let inputs = Arc::new(Mutex::new(Vec::new()));
+        let recv = RecvCtx {
+            inputs: inputs.clone(),
+        };
+
+        let send = self.make_send_ctx(id);
+        let s = send.clone();
+        self.push_op(Box::new(move || f(&recv, &s)), send.dirty.clone());
+
+        (InputPort { id, data: inputs }, send)
+    }
+}
+
+#[test]
+fn test_parallel_df() {
+    let mut df = Dataflow::new();
+
+    let mut sent = false;
+    let output = df.add_source(move |ctx| {
+        if !sent {
+            sent = true;
+            ctx.give_iterator(0..1000);
+        }
+    });
+
+    let evens = Arc::new(Mutex::new(Vec::new()));
+    let odds = Arc::new(Mutex::new(Vec::new()));
+
+    let (e, o) = (evens.clone(), odds.clone());
+    let (even_in, even_out) = df.add_op(move |recv: &RecvCtx<i64>, send| {
+        send.give_iterator(recv.take_all().into_iter().filter(|x| x % 2 == 0));
+    });
+    let (odd_in, odd_out) = df.add_op(move |recv: &RecvCtx<i64>, send| {
+        send.give_iterator(recv.take_all().into_iter().filter(|x| x % 2 == 1));
+    });
+    let even_sink = df.add_sink(move |recv| e.lock().unwrap().extend(recv.take_all()));
+    let odd_sink = df.add_sink(move |recv| o.lock().unwrap().extend(recv.take_all()));
+
+    df.add_edge(output.clone(), even_in);
+    df.add_edge(output, odd_in);
+    df.add_edge(even_out, even_sink);
+    df.add_edge(odd_out, odd_sink);
+
+    df.run(4);
+
+    assert_eq!(evens.lock().unwrap().len(), 500);
+    assert_eq!(odds.lock().unwrap().len(), 500);
+}
@@ -0,0 +1,239 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+use super::{Dataflow, InputPort, RecvCtx, SendCtx};
+
+// The same interface as babyflow::Query, but building a parallel::Dataflow, so
+// that every operator's closure and data has to be Send.
+#[derive(Clone)]
+pub struct Operator<T>
+where
+    T: Clone,
+{
+    df: Rc<RefCell<Dataflow>>,
+    output_port: SendCtx<T>,
+}
+
+impl<T> Operator<T>
+where
+    T: Clone + Send + 'static,
+{
+    pub fn distinct(self) -> Operator<T>
+    where
+        T: Eq + std::hash::Hash,
+    {
+        let mut df = (*self.df).borrow_mut();
+        let mut tab = HashSet::new();
+        let (input, output_port) = df.add_op(move |recv: &RecvCtx<T>, send| {
+            while let Some(v) = recv.pull() {
+                if !tab.contains(&v) {
+                    tab.insert(v.clone());
+                    send.push(v)
+                }
+            }
+        });
+        df.add_edge(self.output_port.clone(), input);
+
+        Operator {
+            df: self.df.clone(),
+            output_port,
+        }
+    }
+
+    pub fn union(self, rhs: Operator<T>) -> Operator<T> {
+        let mut df = (*self.df).borrow_mut();
+        let (input1, input2, output_port) = df.add_op_2(move |recv1, recv2, send| {
+            send.give_vec(&mut recv1.take_all());
+            send.give_vec(&mut recv2.take_all());
+        });
+        df.add_edge(self.output_port.clone(), input1);
+        df.add_edge(rhs.output_port, input2);
+
+        Operator {
+            df: self.df.clone(),
+            output_port,
+        }
+    }
+
+    pub fn filter<F>(self, f: F) -> Operator<T>
+    where
+        F: Fn(&T) -> bool + Send + 'static,
+    {
+        let mut df = (*self.df).borrow_mut();
+        let (input, output_port) = df.add_op(move |recv, send| {
+            let mut vec = recv.take_all();
+            vec.retain(|x| f(x));
+            send.give_vec(&mut vec);
+        });
+        df.add_edge(self.output_port.clone(), input);
+
+        Operator {
+            df: self.df.clone(),
+            output_port,
+        }
+    }
+
+    pub fn map<U, F>(self, f: F) -> Operator<U>
+    where
+        F: Fn(T) -> U + Send + 'static,
+        U: Clone + Send + 'static,
+    {
+        let mut df = (*self.df).borrow_mut();
+        let (input, output_port) = df.add_op(move |recv, send| {
+            send.give_iterator(recv.take_all().into_iter().map(&f));
+        });
+        df.add_edge(self.output_port.clone(), input);
+
+        Operator {
+            df: self.df.clone(),
+            output_port,
+        }
+    }
+
+    pub fn sink<F>(self, f: F)
+    where
+        F: Fn(T) + Send + 'static,
+    {
+        let mut df = (*self.df).borrow_mut();
+        let input = df.add_sink(move |recv| {
+            while let Some(v) = recv.pull() {
+                f(v)
+            }
+        });
+        df.add_edge(self.output_port.clone(), input);
+    }
+}
+
+impl<K, V> Operator<(K, V)>
+where
+    K: Eq + std::hash::Hash + Clone + Send + 'static,
+    V: Clone + Send + 'static,
+{
+    pub fn join<V2>(self, rhs: Operator<(K, V2)>) -> Operator<(K, V, V2)>
+    where
+        V2: Clone + Send + 'static,
+    {
+        let mut df = (*self.df).borrow_mut();
+
+        let mut left_tab: HashMap<K, Vec<V>> = HashMap::new();
+        let mut right_tab: HashMap<K, Vec<V2>> = HashMap::new();
+
+        let (input1, input2, output_port) = df.add_op_2(
+            move |left: &RecvCtx<(K, V)>, right: &RecvCtx<(K, V2)>, send| {
+                while let Some((k, v)) = left.pull() {
+                    left_tab.entry(k.clone()).or_default().push(v.clone());
+                    if let Some(matches) = right_tab.get(&k) {
+                        for v2 in matches {
+                            send.push((k.clone(), v.clone(), v2.clone()));
+                        }
+                    }
+                }
+
+                while let Some((k, v)) = right.pull() {
+                    right_tab.entry(k.clone()).or_default().push(v.clone());
+                    if let Some(matches) = left_tab.get(&k) {
+                        for v2 in matches {
+                            send.push((k.clone(), v2.clone(), v.clone()));
+                        }
+                    }
+                }
+            },
+        );
+
+        df.add_edge(self.output_port.clone(), input1);
+        df.add_edge(rhs.output_port, input2);
+
+        Operator {
+            df: self.df.clone(),
+            output_port,
+        }
+    }
+}
+
+pub struct Query {
+    pub df: Rc<RefCell<Dataflow>>,
+}
+
+impl Query {
+    pub fn new() -> Self {
+        Query {
+            df: Rc::new(RefCell::new(Dataflow::new())),
+        }
+    }
+
+    pub fn run(&mut self, workers: usize) {
+        (*self.df).borrow_mut().run(workers)
+    }
+
+    pub fn wire<T>(&mut self, o: Operator<T>, p: InputPort<T>)
+    where
+        T: Clone + Send + 'static,
+    {
+        (*self.df).borrow_mut().add_edge(o.output_port, p)
+    }
+
+    pub fn concat<T, I>(&mut self, ops: I) -> Operator<T>
+    where
+        T: Clone + Send + 'static,
+        I: IntoIterator<Item = Operator<T>>,
+    {
+        let (p, out) = self.merge();
+        for o in ops {
+            self.wire(o, p.clone())
+        }
+        out
+    }
+
+    pub fn source<T, F>(&mut self, f: F) -> Operator<T>
+    where
+        T: Clone + Send + 'static,
+        F: FnMut(&SendCtx<T>) + Send + 'static,
+    {
+        let output_port = (*self.df).borrow_mut().add_source(f);
+        Operator {
+            df: self.df.clone(),
+            output_port,
+        }
+    }
+
+    pub fn merge<T>(&mut self) -> (InputPort<T>, Operator<T>)
+    where
+        T: Clone + Send + 'static,
+    {
+        let mut df = (*self.df).borrow_mut();
+        let (input, output_port) = df.add_op(move |recv, send| send.give_vec(&mut recv.take_all()));
+
+        (
+            input,
+            Operator {
+                df: self.df.clone(),
+                output_port,
+            },
+        )
+    }
+}
+
+#[test]
+fn test_parallel_query() {
+    use std::sync::{Arc, Mutex};
+
+    let mut q = Query::new();
+
+    let mut op = q.source(|send| send.give_iterator(0..10_000_u64));
+    for _ in 0..8 {
+        op = q.concat((0..2).map(|i| op.clone().filter(move |x| x % 2 == i)));
+    }
+
+    let out = Arc::new(Mutex::new(Vec::new()));
+    let moved = out.clone();
+    op.sink(move |x| moved.lock().unwrap().push(x));
+
+    q.run(4);
+
+    let mut out = out.lock().unwrap().clone();
+    out.sort_unstable();
+    assert_eq!(out, (0..10_000).collect::<Vec<_>>());
+}
@@ -0,0 +1,394 @@
+use std::{
+    cell::Cell,
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+    rc::Rc,
+};
+
+use crate::babyflow::{InputHandle, Operator, Query, RecvCtx, SendCtx};
+
+pub type Time = u64;
+
+// The messages on a timestamped stream. Since operators process their inputs
+// in the order they were sent, a frontier tells everything downstream of it
+// that the data for earlier times has all arrived.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event<T> {
+    // A batch of records, all at the same time.
+    Data(Time, Vec<T>),
+    // A promise that there's no more data at times before this one.
+    Frontier(Time),
+}
+
+// The input to a timestamped stream, which keeps track of its frontier.
+pub struct TimedInput<T> {
+    handle: InputHandle<Event<T>>,
+    frontier: Time,
+}
+
+impl<T> TimedInput<T> {
+    pub fn send(&self, time: Time, batch: Vec<T>) {
+        assert!(
+            time >= self.frontier,
+            "can't send data at {} after advancing to {}",
+            time,
+            self.frontier
+        );
+        self.handle.push(Event::Data(time, batch));
+    }
+
+    // Promises that nothing more will be sent at times before `time`.
+    pub fn advance_to(&mut self, time: Time) {
+        if time > self.frontier {
+            self.frontier = time;
+            self.handle.push(Event::Frontier(time));
+        }
+    }
+
+    pub fn close(&mut self) {
+        self.advance_to(Time::MAX)
+    }
+
+    pub fn frontier(&self) -> Time {
+        self.frontier
+    }
+}
+
+// Reports the frontier at some point in a dataflow, so whoever is driving it
+// can tell when the results for a time are complete.
+#[derive(Clone)]
+pub struct Probe {
+    frontier: Rc<Cell<Time>>,
+}
+
+impl Probe {
+    pub fn frontier(&self) -> Time {
+        self.frontier.get()
+    }
+
+    // Whether every result for `time` has made it to the probe.
+    pub fn done(&self, time: Time) -> bool {
+        self.frontier.get() > time
+    }
+}
+
+#[derive(Clone)]
+pub struct Stream<T>
+where
+    T: Clone,
+{
+    inner: Operator<Event<T>>,
+}
+
+impl Query {
+    pub fn timed_input<T>(&mut self) -> (TimedInput<T>, Stream<T>)
+    where
+        T: Clone + 'static,
+    {
+        let (handle, inner) = self.input();
+        (
+            TimedInput {
+                handle,
+                frontier: 0,
+            },
+            Stream { inner },
+        )
+    }
+}
+
+// Tracks the frontiers of two inputs, reporting when their minimum advances.
+struct Frontiers {
+    left: Time,
+    right: Time,
+}
+
+impl Frontiers {
+    fn new() -> Self {
+        Frontiers { left: 0, right: 0 }
+    }
+
+    fn advance(&mut self, left: Option<Time>, right: Option<Time>) -> Option<Time> {
+        let before = self.left.min(self.right);
+        self.left = self.left.max(left.unwrap_or(0));
+        self.right = self.right.max(right.unwrap_or(0));
+        let after = self.left.min(self.right);
+        if after > before {
+            Some(after)
+        } else {
+            None
+        }
+    }
+}
+
+impl<T> Stream<T>
+where
+    T: Clone + 'static,
+{
+    pub fn inner(self) -> Operator<Event<T>> {
+        self.inner
+    }
+
+    fn batches<U, F>(self, mut f: F) -> Stream<U>
+    where
+        F: FnMut(Time, Vec<T>) -> Vec<U> + 'static,
+        U: Clone + 'static,
+    {
+        let inner = self.inner.unary(move |recv: &RecvCtx<Event<T>>, send| {
+            send.give_iterator(recv.take_all().into_iter().map(|e| match e {
+                Event::Data(t, batch) => Event::Data(t, f(t, batch)),
+                Event::Frontier(t) => Event::Frontier(t),
+            }))
+        });
+        Stream { inner }
+    }
+
+    pub fn map<U, F>(self, f: F) -> Stream<U>
+    where
+        F: Fn(T) -> U + 'static,
+        U: Clone + 'static,
+    {
+        self.batches(move |_, batch| batch.into_iter().map(&f).collect())
+    }
+
+    pub fn filter<F>(self, f: F) -> Stream<T>
+    where
+        F: Fn(&T) -> bool + 'static,
+    {
+        self.batches(move |_, mut batch| {
+            batch.retain(|x| f(x));
+            batch
+        })
+    }
+
+    // Moves each record to the last time of the window of `size` times it
+    // falls in, so that a window is complete once the frontier passes it.
+    pub fn window(self, size: Time) -> Stream<T> {
+        assert!(size > 0, "a window has to be at least one time long");
+        let inner = self.inner.unary(move |recv: &RecvCtx<Event<T>>, send| {
+            send.give_iterator(recv.take_all().into_iter().map(|e| match e {
+                Event::Data(t, batch) => {
+                    Event::Data((t - t % size).saturating_add(size - 1), batch)
+                }
+                e => e,
+            }))
+        });
+        Stream { inner }
+    }
+
+    pub fn concat(self, rhs: Stream<T>) -> Stream<T> {
+        let mut frontiers = Frontiers::new();
+        let inner = self.inner.binary(
+            rhs.inner,
+            move |left: &RecvCtx<Event<T>>, right: &RecvCtx<Event<T>>, send| {
+                for (e, is_left) in left
+                    .take_all()
+                    .into_iter()
+                    .map(|e| (e, true))
+                    .chain(right.take_all().into_iter().map(|e| (e, false)))
+                {
+                    match e {
+                        Event::Data(t, batch) => send.push(Event::Data(t, batch)),
+                        Event::Frontier(t) => {
+                            let f = if is_left {
+                                frontiers.advance(Some(t), None)
+                            } else {
+                                frontiers.advance(None, Some(t))
+                            };
+                            if let Some(f) = f {
+                                send.push(Event::Frontier(f));
+                            }
+                        }
+                    }
+                }
+            },
+        );
+        Stream { inner }
+    }
+
+    // Holds on to the data for each time until the frontier passes it, and
+    // then replaces it with the output of `f`.
+    pub fn reduce_epochs<U, F>(self, f: F) -> Stream<U>
+    where
+        F: Fn(Time, Vec<T>) -> Vec<U> + 'static,
+        U: Clone + 'static,
+    {
+        let mut pending: BTreeMap<Time, Vec<T>> = BTreeMap::new();
+        let inner = self
+            .inner
+            .unary(move |recv: &RecvCtx<Event<T>>, send: &SendCtx<Event<U>>| {
+                for e in recv.take_all() {
+                    match e {
+                        Event::Data(t, mut batch) => {
+                            pending.entry(t).or_default().append(&mut batch)
+                        }
+                        Event::Frontier(frontier) => {
+                            let later = pending.split_off(&frontier);
+                            for (t, batch) in std::mem::replace(&mut pending, later) {
+                                send.push(Event::Data(t, f(t, batch)));
+                            }
+                            send.push(Event::Frontier(frontier));
+                        }
+                    }
+                }
+            });
+        Stream { inner }
+    }
+
+    pub fn probe(self) -> Probe {
+        let frontier = Rc::new(Cell::new(0));
+        let moved = frontier.clone();
+        self.inner
+            .unary(move |recv: &RecvCtx<Event<T>>, _: &SendCtx<()>| {
+                for e in recv.take_all() {
+                    if let Event::Frontier(t) = e {
+                        moved.set(t);
+                    }
+                }
+            });
+        Probe { frontier }
+    }
+
+    pub fn sink<F>(self, f: F)
+    where
+        F: Fn(Event<T>) + 'static,
+    {
+        self.inner
+            .unary(move |recv: &RecvCtx<Event<T>>, _: &SendCtx<()>| {
+                for e in recv.take_all() {
+                    f(e)
+                }
+            });
+    }
+}
+
+impl<K, V> Stream<(K, V)>
+where
+    K: Eq + Hash + Clone + 'static,
+    V: Clone + 'static,
+{
+    // Joins records from the two sides which are at the same time. The state
+    // for a time is thrown away once the frontiers of both sides pass it.
+    pub fn join<V2>(self, rhs: Stream<(K, V2)>) -> Stream<(K, V, V2)>
+    where
+        V2: Clone + 'static,
+    {
+        type Tables<K, V, V2> = (HashMap<K, Vec<V>>, HashMap<K, Vec<V2>>);
+        let mut epochs: BTreeMap<Time, Tables<K, V, V2>> = BTreeMap::new();
+        let mut frontiers = Frontiers::new();
+
+        let inner = self.inner.binary(
+            rhs.inner,
+            move |left: &RecvCtx<Event<(K, V)>>,
+                  right: &RecvCtx<Event<(K, V2)>>,
+                  send: &SendCtx<Event<(K, V, V2)>>| {
+                let mut advanced = None;
+                for e in left.take_all() {
+                    match e {
+                        Event::Data(t, batch) => {
+                            let (left_tab, right_tab) = epochs.entry(t).or_default();
+                            let mut out = Vec::new();
+                            for (k, v) in batch {
+                                for v2 in right_tab.get(&k).into_iter().flatten() {
+                                    out.push((k.clone(), v.clone(), v2.clone()));
+                                }
+                                left_tab.entry(k).or_default().push(v);
+                            }
+                            if !out.is_empty() {
+                                send.push(Event::Data(t, out));
+                            }
+                        }
+                        Event::Frontier(t) => {
+                            advanced = frontiers.advance(Some(t), None).or(advanced)
+                        }
+                    }
+                }
+                for e in right.take_all() {
+                    match e {
+                        Event::Data(t, batch) => {
+                            let (left_tab, right_tab) = epochs.entry(t).or_default();
+                            let mut out = Vec::new();
+                            for (k, v2) in batch {
+                                for v in left_tab.get(&k).into_iter().flatten() {
+                                    out.push((k.clone(), v.clone(), v2.clone()));
+                                }
+                                right_tab.entry(k).or_default().push(v2);
+                            }
+                            if !out.is_empty() {
+                                send.push(Event::Data(t, out));
+                            }
+                        }
+                        Event::Frontier(t) => {
+                            advanced = frontiers.advance(None, Some(t)).or(advanced)
+                        }
+                    }
+                }
+                if let Some(f) = advanced {
+                    epochs = epochs.split_off(&f);
+                    send.push(Event::Frontier(f));
+                }
+            },
+        );
+        Stream { inner }
+    }
+}
+
+#[test]
+fn test_progress() {
+    use std::cell::RefCell;
+
+    let mut q = Query::new();
+    let (mut clicks, click_stream) = q.timed_input();
+    let (mut users, user_stream) = q.timed_input();
+
+    // The number of clicks in each window of ten ticks.
+    let counts = Rc::new(RefCell::new(Vec::new()));
+    let moved = counts.clone();
+    click_stream
+        .clone()
+        .window(10)
+        .reduce_epochs(|_, batch| vec![batch.len()])
+        .sink(move |e| {
+            if let Event::Data(t, batch) = e {
+                moved.borrow_mut().push((t, batch[0]))
+            }
+        });
+
+    let joined = Rc::new(RefCell::new(Vec::new()));
+    let moved = joined.clone();
+    let join = user_stream.join(click_stream);
+    let probe = join.clone().probe();
+    join.sink(move |e| {
+        if let Event::Data(t, batch) = e {
+            moved.borrow_mut().extend(batch.into_iter().map(|r| (t, r)))
+        }
+    });
+
+    clicks.send(1, vec![("a", 1), ("b", 2)]);
+    clicks.send(5, vec![("a", 3)]);
+    users.send(1, vec![("a", "alice")]);
+    users.send(5, vec![("b", "bob")]);
+    clicks.advance_to(12);
+    (*q.df).borrow_mut().run();
+
+    // The first window is complete, but the join can't say it's done with
+    // anything until both of its inputs have advanced.
+    assert_eq!(*counts.borrow(), vec![(9, 3)]);
+    assert_eq!(*joined.borrow(), vec![(1, ("a", "alice", 1))]);
+    assert!(!probe.done(1));
+
+    users.advance_to(12);
+    clicks.send(13, vec![("b", 4)]);
+    clicks.close();
+    users.close();
+    (*q.df).borrow_mut().run();
+    assert!(probe.done(12));
+    assert_eq!(*counts.borrow(), vec![(9, 3), (19, 1)]);
+}
+
+#[test]
+#[should_panic(expected = "at least one time long")]
+fn test_empty_window() {
+    let mut q = Query::new();
+    let (_, stream) = q.timed_input::<i64>();
+    stream.window(0);
+}
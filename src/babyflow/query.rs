@@ -1,10 +1,16 @@
 use std::{
     cell::RefCell,
-    collections::{HashMap, HashSet},
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    io,
+    path::PathBuf,
     rc::Rc,
 };
 
-use crate::babyflow::{Dataflow, InputPort, RecvCtx, SendCtx};
+use crate::babyflow::{
+    spill::{SpillMap, Spiller},
+    Dataflow, Diff, InputHandle, InputPort, RecvCtx, SendCtx, Spill, SpillStats,
+};
 
 #[derive(Clone)]
 pub struct Operator<T>
@@ -19,6 +25,9 @@ impl<T> Operator<T>
 where
     T: Clone,
 {
+    // Sends each record the first time it arrives. With more than one
+    // worker, the records have to be exchanged first for each copy of one to
+    // go to the same worker.
     pub fn distinct(self) -> Operator<T>
     where
         T: Eq + std::hash::Hash + 'static,
@@ -41,6 +50,39 @@ where
         }
     }
 
+    // Like distinct, but the records it's seen are spilled to disk past the
+    // Query's memory limit, and with more than one worker they're exchanged
+    // first.
+    pub fn distinct_spilling(self) -> Operator<T>
+    where
+        T: Eq + std::hash::Hash + Ord + Spill + Send + 'static,
+    {
+        let this = self.exchange(|v| v.clone());
+        let mut df = (*this.df).borrow_mut();
+        let spiller = df.spiller.clone();
+        let mut tab = SpillMap::new(spiller.clone());
+        let (input, output_port) = df.add_op(move |recv: &RecvCtx<T>, send| {
+            while let Some(v) = recv.pull() {
+                let inserted = match tab.contains_key(&v) {
+                    Ok(true) => Ok(false),
+                    Ok(false) => tab.insert(v.clone(), ()).map(|_| true),
+                    Err(e) => Err(e),
+                };
+                match inserted {
+                    Ok(true) => send.push(v),
+                    Ok(false) => {}
+                    Err(e) => spiller.as_ref().unwrap().fail(e),
+                }
+            }
+        });
+        df.add_edge(this.output_port.clone(), input);
+
+        Operator {
+            df: this.df.clone(),
+            output_port,
+        }
+    }
+
     #[allow(unused)]
     pub fn union(self, rhs: Operator<T>) -> Operator<T>
     where
@@ -97,6 +139,98 @@ where
         }
     }
 
+    // Routes each record to the worker that owns the hash of its key. Stateful
+    // operators only see all the records for a key if they're exchanged on
+    // that key first, which join_spilling, distinct_spilling and
+    // arrange_by_key do themselves.
+    // Outside of babyflow::execute this does nothing.
+    pub fn exchange<K, F>(self, key: F) -> Operator<T>
+    where
+        K: Hash,
+        F: Fn(&T) -> K + 'static,
+        T: Send + 'static,
+    {
+        let mut df = (*self.df).borrow_mut();
+        let op = df.operators.len();
+        let (index, peers) = match &df.worker {
+            Some(w) if w.peers > 1 => (w.index, w.peers),
+            _ => {
+                drop(df);
+                return self;
+            }
+        };
+
+        // Data sent to us by the other workers lands here.
+        let inbox = Rc::new(RefCell::new(Vec::new()));
+        let received = inbox.clone();
+        let (channel, fabric) = df
+            .worker
+            .as_mut()
+            .unwrap()
+            .channel(op, move |mut batch| inbox.borrow_mut().append(&mut batch));
+
+        let (input, output_port) = df.add_op(move |recv: &RecvCtx<T>, send| {
+            let mut outgoing: Vec<_> = (0..peers).map(|_| Vec::new()).collect();
+            for v in recv.take_all() {
+                let mut hasher = DefaultHasher::new();
+                key(&v).hash(&mut hasher);
+                outgoing[(hasher.finish() % peers as u64) as usize].push(v);
+            }
+            let mut local = std::mem::take(&mut outgoing[index]);
+            for (to, batch) in outgoing.into_iter().enumerate() {
+                if !batch.is_empty() {
+                    fabric.send(channel, to, batch);
+                }
+            }
+            local.append(&mut *received.borrow_mut());
+            if !local.is_empty() {
+                send.give_vec(&mut local);
+            }
+        });
+        df.add_edge(self.output_port.clone(), input);
+
+        Operator {
+            df: self.df.clone(),
+            output_port,
+        }
+    }
+
+    // Adds an arbitrary operator reading from this one.
+    pub fn unary<U, F>(self, f: F) -> Operator<U>
+    where
+        F: FnMut(&RecvCtx<T>, &SendCtx<U>) + 'static,
+        T: 'static,
+        U: Clone + 'static,
+    {
+        let mut df = (*self.df).borrow_mut();
+        let (input, output_port) = df.add_op(f);
+        df.add_edge(self.output_port.clone(), input);
+
+        Operator {
+            df: self.df.clone(),
+            output_port,
+        }
+    }
+
+    // Adds an arbitrary operator reading from this one and rhs.
+    pub fn binary<T2, U, F>(self, rhs: Operator<T2>, f: F) -> Operator<U>
+    where
+        F: FnMut(&RecvCtx<T>, &RecvCtx<T2>, &SendCtx<U>) + 'static,
+        T: 'static,
+        T2: Clone + 'static,
+        U: Clone + 'static,
+    {
+        let mut df = (*self.df).borrow_mut();
+        let (input1, input2, output_port) = df.add_op_2(f);
+        df.add_edge(self.output_port.clone(), input1);
+        df.add_edge(rhs.output_port, input2);
+
+        Operator {
+            df: self.df.clone(),
+            output_port,
+        }
+    }
+
     pub fn sink<F>(self, f: F)
     where
         F: Fn(T) + 'static,
@@ -117,6 +251,8 @@ where
     K: Eq + std::hash::Hash + Clone + 'static,
     V: Clone + 'static,
 {
+    // Sends each pair of records with the same key. With more than one
+    // worker, both sides have to be exchanged on their keys first.
     pub fn join<V2>(self, rhs: Operator<(K, V2)>) -> Operator<(K, V, V2)>
     where
         V2: Clone + 'static,
@@ -129,10 +265,7 @@ where
         let (input1, input2, output_port) = df.add_op_2(
             move |left: &RecvCtx<(K, V)>, right: &RecvCtx<(K, V2)>, send| {
                 while let Some((k, v)) = left.pull() {
-                    left_tab
-                        .entry(k.clone())
-                        .or_insert_with(Vec::new)
-                        .push(v.clone());
+                    left_tab.entry(k.clone()).or_default().push(v.clone());
                     if let Some(matches) = right_tab.get(&k) {
                         for v2 in matches {
                             send.push((k.clone(), v.clone(), v2.clone()));
@@ -141,10 +274,7 @@ where
                 }
 
                 while let Some((k, v)) = right.pull() {
-                    right_tab
-                        .entry(k.clone())
-                        .or_insert_with(Vec::new)
-                        .push(v.clone());
+                    right_tab.entry(k.clone()).or_default().push(v.clone());
                     if let Some(matches) = left_tab.get(&k) {
                         for v2 in matches {
                             send.push((k.clone(), v2.clone(), v.clone()));
@@ -162,6 +292,159 @@ where
             output_port,
         }
     }
+
+    // Like join, but each side is kept in an index which is spilled to disk
+    // past the Query's memory limit, and with more than one worker the
+    // records are exchanged on their keys first.
+    pub fn join_spilling<V2>(self, rhs: Operator<(K, V2)>) -> Operator<(K, V, V2)>
+    where
+        K: Ord + Spill + Send,
+        V: Spill + Send,
+        V2: Clone + Spill + Send + 'static,
+    {
+        self.arrange_by_key().join(&rhs.arrange_by_key())
+    }
+
+    // Indexes the records by key, keeping every one of them. Any number of
+    // joins can read from the index, rather than each keeping its own copy of
+    // the records. With more than one worker, the records are first exchanged
+    // on their key, so each worker's index has every record for its keys.
+    pub fn arrange_by_key(self) -> Arranged<K, V>
+    where
+        K: Ord + Spill + Send,
+        V: Spill + Send,
+    {
+        let this = self.exchange(|(k, _)| k.clone());
+        let spiller = (*this.df).borrow().spiller.clone();
+        let trace = Rc::new(RefCell::new(Trace {
+            index: SpillMap::new(spiller.clone()),
+            len: 0,
+            spiller,
+        }));
+        let moved = trace.clone();
+        let stream = this.unary(move |recv: &RecvCtx<(K, V)>, send| {
+            let mut records = recv.take_all();
+            let mut trace = (*moved).borrow_mut();
+            for (k, v) in &records {
+                let seq = trace.len;
+                trace.len += 1;
+                if let Err(e) = trace.index.insert(k.clone(), (seq, v.clone())) {
+                    trace.spiller.as_ref().unwrap().fail(e);
+                }
+            }
+            send.give_vec(&mut records);
+        });
+        Arranged { stream, trace }
+    }
+
+    // Folds the values for each key into an accumulator starting at `init`,
+    // sending the new (key, accumulator) whenever one changes. Later updates
+    // for a key supersede the earlier ones.
+    pub fn reduce<A, F>(self, init: A, f: F) -> Operator<(K, A)>
+    where
+        A: Clone + PartialEq + 'static,
+        F: Fn(&mut A, V) + 'static,
+    {
+        let mut state: HashMap<K, A> = HashMap::new();
+        self.unary(move |recv: &RecvCtx<(K, V)>, send| {
+            let mut changed = Vec::new();
+            for (k, v) in recv.take_all() {
+                let acc = state.entry(k.clone()).or_insert_with(|| init.clone());
+                let before = acc.clone();
+                f(acc, v);
+                if *acc != before {
+                    changed.push(k);
+                }
+            }
+            let mut seen = HashSet::new();
+            changed.retain(|k| seen.insert(k.clone()));
+            send.give_iterator(changed.into_iter().map(|k| {
+                let acc = state[&k].clone();
+                (k, acc)
+            }));
+        })
+    }
+}
+
+// A stream of records, along with an index of them by key that's shared by
+// everything reading from it.
+#[derive(Clone)]
+pub struct Arranged<K, V>
+where
+    K: Clone,
+    V: Clone,
+{
+    // The records, sent once they've been added to the index.
+    stream: Operator<(K, V)>,
+    trace: Rc<RefCell<Trace<K, V>>>,
+}
+
+// Every record an arrangement has been sent, numbered in the order they
+// arrived. A reader can have been sent fewer of them than are in the index,
+// since it's filled in as soon as records arrive, so readers only look at the
+// records they've already been sent.
+struct Trace<K, V> {
+    index: SpillMap<K, (usize, V)>,
+    len: usize,
+    spiller: Option<Rc<Spiller>>,
+}
+
+impl<K, V> Trace<K, V>
+where
+    K: Eq + Hash + Ord + Clone + Spill,
+    V: Spill,
+{
+    // Calls f with the values for a key among the first `seen` records. If
+    // they can't be read back from disk, the error is left for the Query to
+    // report.
+    fn get(&self, k: &K, seen: usize, mut f: impl FnMut(&V)) {
+        let read = self.index.get(k, |(seq, v)| {
+            if *seq < seen {
+                f(v)
+            }
+        });
+        if let Err(e) = read {
+            self.spiller.as_ref().unwrap().fail(e);
+        }
+    }
+}
+
+impl<K, V> Arranged<K, V>
+where
+    K: Eq + Hash + Ord + Clone + Spill + 'static,
+    V: Clone + Spill + 'static,
+{
+    // Sends each pair of records with the same key, once the later of the two
+    // arrives.
+    pub fn join<V2>(&self, rhs: &Arranged<K, V2>) -> Operator<(K, V, V2)>
+    where
+        V2: Clone + Spill + 'static,
+    {
+        let left_trace = self.trace.clone();
+        let right_trace = rhs.trace.clone();
+        // How many records each side has sent us.
+        let mut left_seen = 0;
+        let mut right_seen = 0;
+        self.stream.clone().binary(
+            rhs.stream.clone(),
+            move |left: &RecvCtx<(K, V)>, right: &RecvCtx<(K, V2)>, send| {
+                let left_trace = (*left_trace).borrow();
+                let right_trace = (*right_trace).borrow();
+                for (k, v) in left.take_all() {
+                    left_seen += 1;
+                    right_trace.get(&k, right_seen, |v2| {
+                        send.push((k.clone(), v.clone(), v2.clone()))
+                    });
+                }
+                for (k, v2) in right.take_all() {
+                    right_seen += 1;
+                    left_trace.get(&k, left_seen, |v| {
+                        send.push((k.clone(), v.clone(), v2.clone()))
+                    });
+                }
+            },
+        )
+    }
 }
 
 pub struct Query {
@@ -175,6 +458,48 @@ impl Query {
         }
     }
 
+    pub fn index(&self) -> usize {
+        (*self.df).borrow().worker.as_ref().map_or(0, |w| w.index)
+    }
+
+    pub fn peers(&self) -> usize {
+        (*self.df).borrow().worker.as_ref().map_or(1, |w| w.peers)
+    }
+
+    // Caps each index that arrange_by_key and distinct_spilling add after
+    // this keep in memory at about `limit` bytes, so join_spilling can keep
+    // about twice that. Once one would go over, it's written out to sorted
+    // runs in temporary files in `dir`, which are read back as needed and
+    // merged together when there start to be too many. Other operators'
+    // state, like join's, multiway_join's and a Collection's, isn't capped.
+    pub fn spill(&mut self, limit: usize, dir: impl Into<PathBuf>) {
+        (*self.df).borrow_mut().spiller = Some(Rc::new(Spiller {
+            limit,
+            dir: dir.into(),
+            stats: RefCell::new(SpillStats::default()),
+            error: RefCell::new(None),
+        }));
+    }
+
+    // The first error an operator ran into spilling to or reading back from
+    // disk, if there's been one since the last call. Once there has, the
+    // operators' output can't be relied on.
+    pub fn spill_error(&self) -> Option<io::Error> {
+        let df = (*self.df).borrow();
+        let spiller = df.spiller.as_ref()?;
+        let error = spiller.error.borrow_mut().take();
+        error
+    }
+
+    // How much has been spilled so far.
+    pub fn spill_stats(&self) -> SpillStats {
+        (*self.df)
+            .borrow()
+            .spiller
+            .as_ref()
+            .map_or_else(SpillStats::default, |spiller| *spiller.stats.borrow())
+    }
+
     pub fn wire<T>(&mut self, o: Operator<T>, p: InputPort<T>)
     where
         T: Clone + 'static,
@@ -206,6 +531,110 @@ impl Query {
         }
     }
 
+    // A source that's fed from outside the dataflow: data given to the handle
+    // between calls to run only wakes up the operators downstream of it.
+    pub fn input<T>(&mut self) -> (InputHandle<T>, Operator<T>)
+    where
+        T: Clone + 'static,
+    {
+        let (handle, output_port) = (*self.df).borrow_mut().add_input();
+        (
+            handle,
+            Operator {
+                df: self.df.clone(),
+                output_port,
+            },
+        )
+    }
+
+    // Joins any number of inputs at once, with a worst-case optimal generic
+    // join. Each input comes with the variable each of its columns binds,
+    // where the variables are numbered in the order they should be bound, and
+    // each output row has the value of every variable, in that order. Every
+    // variable has to be bound by some input, and no input can bind one
+    // twice.
+    //
+    // Joining two inputs at a time can build intermediate results much bigger
+    // than the output when the join is cyclic, like finding triangles. This
+    // instead works out the values of one variable at a time, by intersecting
+    // the values every input containing it allows, given the variables bound
+    // so far, which never does more work than the largest the output could
+    // be.
+    //
+    // The inputs and output are collections: new rows on an input are matched
+    // against everything the other inputs have so far, so each output row is
+    // sent once when the last of the rows making it up arrives, and is
+    // retracted when the first of them is. Unlike Collection::join, the inputs
+    // are sets, so adding a row that's already there or retracting one that
+    // isn't does nothing.
+    pub fn multiway_join<T, R>(&mut self, inputs: Vec<JoinInput<R>>) -> Operator<(Vec<T>, Diff)>
+    where
+        T: Eq + Hash + Clone + 'static,
+        R: AsRef<[T]> + Clone + 'static,
+    {
+        let vars = inputs
+            .iter()
+            .flat_map(|(_, binds)| binds)
+            .map(|v| v + 1)
+            .max()
+            .unwrap_or(0);
+        for var in 0..vars {
+            assert!(
+                inputs.iter().any(|(_, binds)| binds.contains(&var)),
+                "variable {} isn't bound by any input",
+                var
+            );
+        }
+        let mut tries: Vec<Trie<T>> = inputs.iter().map(|(_, binds)| Trie::new(binds)).collect();
+
+        let tagged: Vec<_> = inputs
+            .into_iter()
+            .enumerate()
+            .map(|(i, (op, _))| op.map(move |row| (i, row)))
+            .collect();
+        self.concat(tagged)
+            .unary(move |recv: &RecvCtx<(usize, (R, Diff))>, send| {
+                let rows = recv.take_all();
+                let mut out = Vec::new();
+                for run in rows.chunk_by(|(a, _), (b, _)| a == b) {
+                    let i = run[0].0;
+                    let mut changes: HashMap<Vec<T>, Diff> = HashMap::new();
+                    for (_, (row, d)) in run {
+                        *changes.entry(row.as_ref().to_vec()).or_default() += d;
+                    }
+                    let mut added = tries[i].empty();
+                    let mut removed = tries[i].empty();
+                    for (row, d) in &changes {
+                        if *d > 0 && !tries[i].contains(row) {
+                            added.insert(row);
+                        } else if *d < 0 && tries[i].contains(row) {
+                            removed.insert(row);
+                        }
+                    }
+                    for (delta, diff) in [(&removed, -1), (&added, 1)] {
+                        let view: Vec<&Trie<T>> = tries
+                            .iter()
+                            .enumerate()
+                            .map(|(j, trie)| if j == i { delta } else { trie })
+                            .collect();
+                        if view.iter().all(|trie| trie.len > 0) {
+                            let mut found = Vec::new();
+                            generic_join(&view, vars, &mut Vec::new(), &mut found);
+                            out.extend(found.into_iter().map(|row| (row, diff)));
+                        }
+                    }
+                    for (row, d) in &changes {
+                        if *d > 0 {
+                            tries[i].insert(row);
+                        } else if *d < 0 {
+                            tries[i].remove(row);
+                        }
+                    }
+                }
+                send.give_vec(&mut out);
+            })
+    }
+
     pub fn merge<T>(&mut self) -> (InputPort<T>, Operator<T>)
     where
         T: Clone + 'static,
@@ -223,6 +652,138 @@ impl Query {
     }
 }
 
+// An input to a multiway join, and the variable each of its columns binds.
+type JoinInput<R> = (Operator<(R, Diff)>, Vec<usize>);
+
+// The distinct rows seen on one input to a multiway join. Their columns are
+// put in the order their variables are bound, and each level maps the values
+// of the columns before it to the values of its own column that follow them.
+struct Trie<T> {
+    // The columns, in the order they're bound.
+    columns: Vec<usize>,
+    // The variable each level binds.
+    vars: Vec<usize>,
+    levels: Vec<HashMap<Vec<T>, HashSet<T>>>,
+    len: usize,
+}
+
+impl<T> Trie<T>
+where
+    T: Eq + Hash + Clone,
+{
+    fn new(binds: &[usize]) -> Self {
+        let mut columns: Vec<usize> = (0..binds.len()).collect();
+        columns.sort_by_key(|col| binds[*col]);
+        for pair in columns.windows(2) {
+            assert!(
+                binds[pair[0]] != binds[pair[1]],
+                "an input binds a variable twice"
+            );
+        }
+        Trie {
+            vars: columns.iter().map(|col| binds[*col]).collect(),
+            levels: columns.iter().map(|_| HashMap::new()).collect(),
+            columns,
+            len: 0,
+        }
+    }
+
+    fn empty(&self) -> Self {
+        Trie {
+            columns: self.columns.clone(),
+            vars: self.vars.clone(),
+            levels: self.columns.iter().map(|_| HashMap::new()).collect(),
+            len: 0,
+        }
+    }
+
+    fn key(&self, row: &[T]) -> Vec<T> {
+        self.columns.iter().map(|col| row[*col].clone()).collect()
+    }
+
+    fn contains(&self, row: &[T]) -> bool {
+        let key = self.key(row);
+        match key.split_last() {
+            Some((last, prefix)) => self.levels[prefix.len()]
+                .get(prefix)
+                .is_some_and(|values| values.contains(last)),
+            None => self.len > 0,
+        }
+    }
+
+    fn insert(&mut self, row: &[T]) {
+        if self.contains(row) {
+            return;
+        }
+        let key = self.key(row);
+        for (i, level) in self.levels.iter_mut().enumerate() {
+            level
+                .entry(key[..i].to_vec())
+                .or_default()
+                .insert(key[i].clone());
+        }
+        self.len += 1;
+    }
+
+    // Removes a row, along with any prefixes that no longer lead anywhere.
+    fn remove(&mut self, row: &[T]) {
+        if !self.contains(row) {
+            return;
+        }
+        let key = self.key(row);
+        for (i, level) in self.levels.iter_mut().enumerate().rev() {
+            let values = level.get_mut(&key[..i]).unwrap();
+            values.remove(&key[i]);
+            if !values.is_empty() {
+                break;
+            }
+            level.remove(&key[..i]);
+        }
+        self.len -= 1;
+    }
+
+    // The values this allows for `var`, given the values of the variables
+    // bound before it.
+    fn values(&self, var: usize, binding: &[T]) -> Option<&HashSet<T>> {
+        let level = self.vars.iter().position(|v| *v == var)?;
+        let prefix: Vec<T> = self.vars[..level]
+            .iter()
+            .map(|v| binding[*v].clone())
+            .collect();
+        self.levels[level].get(&prefix)
+    }
+}
+
+// Extends a binding of the first few variables to every binding of all of
+// them that every trie allows.
+fn generic_join<T>(tries: &[&Trie<T>], vars: usize, binding: &mut Vec<T>, out: &mut Vec<Vec<T>>)
+where
+    T: Eq + Hash + Clone,
+{
+    let var = binding.len();
+    if var == vars {
+        out.push(binding.clone());
+        return;
+    }
+    let mut candidates = Vec::new();
+    for trie in tries.iter().filter(|trie| trie.vars.contains(&var)) {
+        match trie.values(var, binding) {
+            Some(values) => candidates.push(values),
+            None => return,
+        }
+    }
+    // Only the smallest set has to be gone through.
+    candidates.sort_by_key(|values| values.len());
+    let (smallest, rest) = candidates.split_first().unwrap();
+    for value in smallest.iter() {
+        if rest.iter().all(|values| values.contains(value)) {
+            binding.push(value.clone());
+            generic_join(tries, vars, binding, out);
+            binding.pop();
+        }
+    }
+}
+
 #[test]
 fn test_query() {
     let mut q = Query::new();
@@ -242,3 +803,154 @@ fn test_query() {
 
     (*q.df).borrow_mut().run();
 }
+
+#[test]
+fn test_query_input() {
+    let mut q = Query::new();
+
+    let (edges, edge_op) = q.input();
+    let (nodes, node_op) = q.input();
+
+    let out = Rc::new(RefCell::new(Vec::new()));
+    let moved = out.clone();
+    node_op
+        .map(|n: i64| (n, ()))
+        .join(edge_op)
+        .map(|(from, (), to): (i64, (), i64)| (from, to))
+        .distinct()
+        .sink(move |e| (*moved).borrow_mut().push(e));
+
+    edges.give_vec(&mut vec![(1, 2), (2, 3)]);
+    nodes.push(1);
+    (*q.df).borrow_mut().run();
+    assert_eq!(*(*out).borrow(), vec![(1, 2)]);
+
+    // The join remembers what it's seen, so new data on either side is matched
+    // against the old.
+    nodes.push(2);
+    edges.push((1, 4));
+    (*q.df).borrow_mut().run();
+    let mut results = (*out).borrow().clone();
+    results.sort_unstable();
+    assert_eq!(results, vec![(1, 2), (1, 4), (2, 3)]);
+}
+
+#[test]
+fn test_multiway_join() {
+    let mut q = Query::new();
+
+    let (edges, edge_op) = q.input();
+    let out = Rc::new(RefCell::new(Vec::new()));
+    let moved = out.clone();
+    // Triangles a -> b -> c -> a, with the variables bound in the order a, b,
+    // c.
+    q.multiway_join(vec![
+        (edge_op.clone(), vec![0, 1]),
+        (edge_op.clone(), vec![1, 2]),
+        (edge_op, vec![2, 0]),
+    ])
+    .sink(move |t: (Vec<i64>, Diff)| (*moved).borrow_mut().push(t));
+
+    edges.give_vec(&mut vec![(vec![1, 2], 1), (vec![2, 3], 1), (vec![3, 4], 1)]);
+    (*q.df).borrow_mut().run();
+    assert!((*out).borrow().is_empty());
+
+    // Closing the triangle finds it from each corner, once each, and a
+    // duplicate edge doesn't find anything new.
+    edges.give_vec(&mut vec![(vec![3, 1], 1), (vec![1, 2], 1)]);
+    (*q.df).borrow_mut().run();
+    let mut results = (*out).borrow_mut().split_off(0);
+    results.sort_unstable();
+    assert_eq!(
+        results,
+        vec![(vec![1, 2, 3], 1), (vec![2, 3, 1], 1), (vec![3, 1, 2], 1)]
+    );
+
+    // Taking out one of its edges retracts it, but taking out an edge that
+    // isn't there doesn't.
+    edges.give_vec(&mut vec![(vec![2, 3], -1), (vec![5, 6], -1)]);
+    (*q.df).borrow_mut().run();
+    let mut results = (*out).borrow_mut().split_off(0);
+    results.sort_unstable();
+    assert_eq!(
+        results,
+        vec![
+            (vec![1, 2, 3], -1),
+            (vec![2, 3, 1], -1),
+            (vec![3, 1, 2], -1)
+        ]
+    );
+}
+
+#[test]
+fn test_arrange_by_key() {
+    let mut q = Query::new();
+
+    let (edges, edge_op) = q.input();
+    let (nodes, node_op) = q.input();
+    let out = Rc::new(RefCell::new(Vec::new()));
+
+    // Paths of length two, the edges out of some nodes, and pairs of edges
+    // out of the same node, all reading from one index of the edges.
+    let by_src = edge_op.clone().arrange_by_key();
+    let by_dst = edge_op
+        .map(|(from, to): (i64, i64)| (to, from))
+        .arrange_by_key();
+    let moved = out.clone();
+    by_dst
+        .join(&by_src)
+        .sink(move |(_, from, to)| (*moved).borrow_mut().push(("path", from, to)));
+    let moved = out.clone();
+    node_op
+        .map(|n: i64| (n, ()))
+        .arrange_by_key()
+        .join(&by_src)
+        .sink(move |(from, (), to)| (*moved).borrow_mut().push(("out", from, to)));
+    let moved = out.clone();
+    by_src
+        .join(&by_src)
+        .filter(|(_, a, b)| a < b)
+        .sink(move |(_, a, b)| (*moved).borrow_mut().push(("fork", a, b)));
+
+    edges.give_vec(&mut vec![(1, 2), (2, 3)]);
+    nodes.push(2);
+    (*q.df).borrow_mut().run();
+    edges.give_vec(&mut vec![(3, 1), (2, 4)]);
+    (*q.df).borrow_mut().run();
+
+    let mut results = (*out).borrow().clone();
+    results.sort_unstable();
+    assert_eq!(
+        results,
+        vec![
+            ("fork", 3, 4),
+            ("out", 2, 3),
+            ("out", 2, 4),
+            ("path", 1, 3),
+            ("path", 1, 4),
+            ("path", 2, 1),
+            ("path", 3, 2),
+        ]
+    );
+}
+
+#[test]
+fn test_spill_error() {
+    let mut q = Query::new();
+    q.spill(1 << 10, std::env::temp_dir().join("babyflow-missing"));
+
+    let (nums, num_op) = q.input();
+    num_op.distinct_spilling().sink(|_: i64| {});
+
+    nums.give_vec(&mut (0..10).collect());
+    (*q.df).borrow_mut().run();
+    assert!(q.spill_error().is_none());
+
+    // Going over the limit tries to write a run to a directory that isn't
+    // there, and the error is kept until it's asked for.
+    nums.give_vec(&mut (0..1000).collect());
+    (*q.df).borrow_mut().run();
+    let e = q.spill_error().unwrap();
+    assert!(e.to_string().contains("babyflow-missing"), "{}", e);
+    assert!(q.spill_error().is_none());
+}
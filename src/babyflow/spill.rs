@@ -0,0 +1,583 @@
+use std::{
+    cell::RefCell,
+    cmp::Reverse,
+    collections::{hash_map::DefaultHasher, BinaryHeap, HashMap},
+    fs::{self, File, OpenOptions},
+    hash::{Hash, Hasher},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    mem,
+    ops::AddAssign,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// Records which can be written out to disk by an operator whose state has
+// gotten too big, and read back in later.
+pub trait Spill: Sized {
+    fn encode(&self, buf: &mut Vec<u8>);
+    fn decode(buf: &mut &[u8]) -> Self;
+
+    // About how many bytes of memory this takes up.
+    fn footprint(&self) -> usize {
+        mem::size_of::<Self>()
+    }
+}
+
+fn take<'a>(buf: &mut &'a [u8], n: usize) -> &'a [u8] {
+    let (head, tail) = buf.split_at(n);
+    *buf = tail;
+    head
+}
+
+macro_rules! spill_int {
+    ($($t:ty),*) => {
+        $(
+            impl Spill for $t {
+                fn encode(&self, buf: &mut Vec<u8>) {
+                    buf.extend_from_slice(&self.to_le_bytes());
+                }
+
+                fn decode(buf: &mut &[u8]) -> Self {
+                    let mut bytes = [0; mem::size_of::<$t>()];
+                    bytes.copy_from_slice(take(buf, mem::size_of::<$t>()));
+                    <$t>::from_le_bytes(bytes)
+                }
+            }
+        )*
+    };
+}
+
+spill_int!(i32, i64, isize, u8, u32, u64, usize, f64);
+
+impl Spill for bool {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(*self as u8);
+    }
+
+    fn decode(buf: &mut &[u8]) -> Self {
+        u8::decode(buf) != 0
+    }
+}
+
+impl Spill for () {
+    fn encode(&self, _: &mut Vec<u8>) {}
+
+    fn decode(_: &mut &[u8]) -> Self {}
+}
+
+impl Spill for String {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.len().encode(buf);
+        buf.extend_from_slice(self.as_bytes());
+    }
+
+    fn decode(buf: &mut &[u8]) -> Self {
+        let len = usize::decode(buf);
+        String::from_utf8(take(buf, len).to_vec()).unwrap()
+    }
+
+    fn footprint(&self) -> usize {
+        mem::size_of::<Self>() + self.capacity()
+    }
+}
+
+impl<T: Spill> Spill for Vec<T> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.len().encode(buf);
+        for x in self {
+            x.encode(buf);
+        }
+    }
+
+    fn decode(buf: &mut &[u8]) -> Self {
+        let len = usize::decode(buf);
+        (0..len).map(|_| T::decode(buf)).collect()
+    }
+
+    fn footprint(&self) -> usize {
+        mem::size_of::<Self>() + self.iter().map(|x| x.footprint()).sum::<usize>()
+    }
+}
+
+impl<A: Spill, B: Spill> Spill for (A, B) {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.0.encode(buf);
+        self.1.encode(buf);
+    }
+
+    fn decode(buf: &mut &[u8]) -> Self {
+        let a = A::decode(buf);
+        (a, B::decode(buf))
+    }
+
+    fn footprint(&self) -> usize {
+        self.0.footprint() + self.1.footprint()
+    }
+}
+
+impl<A: Spill, B: Spill, C: Spill> Spill for (A, B, C) {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.0.encode(buf);
+        self.1.encode(buf);
+        self.2.encode(buf);
+    }
+
+    fn decode(buf: &mut &[u8]) -> Self {
+        let a = A::decode(buf);
+        let b = B::decode(buf);
+        (a, b, C::decode(buf))
+    }
+
+    fn footprint(&self) -> usize {
+        self.0.footprint() + self.1.footprint() + self.2.footprint()
+    }
+}
+
+// How much a dataflow's operators have written out to disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpillStats {
+    // Records written out because an operator ran out of memory.
+    pub records: usize,
+    // Bytes written, including when runs are merged.
+    pub bytes: u64,
+    // Runs written, including ones made by merging others.
+    pub runs: usize,
+    pub merges: usize,
+}
+
+impl AddAssign for SpillStats {
+    fn add_assign(&mut self, other: Self) {
+        self.records += other.records;
+        self.bytes += other.bytes;
+        self.runs += other.runs;
+        self.merges += other.merges;
+    }
+}
+
+// Where a dataflow's operators spill to, and how much they can keep in memory
+// before they do.
+pub(crate) struct Spiller {
+    pub limit: usize,
+    pub dir: PathBuf,
+    pub stats: RefCell<SpillStats>,
+    // The first error an operator ran into reading or writing a run, since
+    // operators have nowhere to return it to.
+    pub error: RefCell<Option<io::Error>>,
+}
+
+impl Spiller {
+    pub fn fail(&self, e: io::Error) {
+        self.error.borrow_mut().get_or_insert(e);
+    }
+}
+
+// Adds the path of the run to an error reading or writing it.
+fn annotate(e: io::Error, action: &str, path: &Path) -> io::Error {
+    io::Error::new(
+        e.kind(),
+        format!("couldn't {} {}: {}", action, path.display(), e),
+    )
+}
+
+// About how many bytes of a run are read to look up a key.
+const BLOCK: usize = 16 << 10;
+// How many runs a map can have before they're merged into one.
+const MAX_RUNS: usize = 8;
+
+// A map from keys to lists of values which, once it takes up more memory than
+// the limit, writes everything it has to a run: a temporary file of the keys
+// in sorted order, each followed by its values. Looking up a key checks each
+// of the runs, oldest first, and then memory, so a key's values come back in
+// the order they were inserted. Without a spiller it's just a HashMap.
+pub(crate) struct SpillMap<K, V> {
+    memory: HashMap<K, Vec<V>>,
+    footprint: usize,
+    runs: Vec<Run<K>>,
+    spiller: Option<Rc<Spiller>>,
+}
+
+impl<K, V> SpillMap<K, V>
+where
+    K: Eq + Hash + Ord + Clone + Spill,
+    V: Spill,
+{
+    pub fn new(spiller: Option<Rc<Spiller>>) -> Self {
+        SpillMap {
+            memory: HashMap::new(),
+            footprint: 0,
+            runs: Vec::new(),
+            spiller,
+        }
+    }
+
+    pub fn insert(&mut self, k: K, v: V) -> io::Result<()> {
+        self.footprint += v.footprint();
+        match self.memory.get_mut(&k) {
+            Some(values) => values.push(v),
+            None => {
+                self.footprint += k.footprint() + mem::size_of::<Vec<V>>();
+                self.memory.insert(k, vec![v]);
+            }
+        }
+        if let Some(spiller) = &self.spiller {
+            if self.footprint > spiller.limit {
+                let spiller = spiller.clone();
+                self.spill(&spiller)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn contains_key(&self, k: &K) -> io::Result<bool> {
+        if self.memory.contains_key(k) {
+            return Ok(true);
+        }
+        for run in &self.runs {
+            if run.find(k)?.is_some() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // Calls f with each of the values for a key.
+    pub fn get(&self, k: &K, mut f: impl FnMut(&V)) -> io::Result<()> {
+        for run in &self.runs {
+            if let Some(block) = run.find(k)? {
+                let mut buf = &block[..];
+                let n = usize::decode(&mut buf);
+                for _ in 0..n {
+                    f(&V::decode(&mut buf));
+                }
+            }
+        }
+        for v in self.memory.get(k).into_iter().flatten() {
+            f(v);
+        }
+        Ok(())
+    }
+
+    // Writes everything in memory out to a run. If that fails, the map is
+    // left as it was.
+    fn spill(&mut self, spiller: &Spiller) -> io::Result<()> {
+        let mut keys: Vec<_> = self.memory.keys().collect();
+        keys.sort_unstable();
+
+        let mut writer = RunWriter::new(spiller, keys.len())?;
+        let mut records = 0;
+        for k in keys {
+            let values = &self.memory[k];
+            records += values.len();
+            let mut buf = Vec::new();
+            values.len().encode(&mut buf);
+            for v in values {
+                v.encode(&mut buf);
+            }
+            writer.push(k.clone(), &buf)?;
+        }
+        self.runs.push(writer.finish(spiller)?);
+        self.memory.clear();
+        self.footprint = 0;
+        spiller.stats.borrow_mut().records += records;
+
+        if self.runs.len() > MAX_RUNS {
+            self.merge(spiller)?;
+        }
+        Ok(())
+    }
+
+    // Merges all the runs into one, so lookups only have to read one block.
+    fn merge(&mut self, spiller: &Spiller) -> io::Result<()> {
+        let keys = self.runs.iter().map(|run| run.keys).sum();
+        let mut readers: Vec<_> = self.runs.iter().map(|run| run.entries()).collect();
+        // The next key from each run, smallest first, with older runs first
+        // for the same key.
+        let mut heap = BinaryHeap::new();
+        for (i, reader) in readers.iter_mut().enumerate() {
+            if let Some((k, values)) = reader.next().transpose()? {
+                heap.push(Reverse((k, i, values)));
+            }
+        }
+
+        let mut writer = RunWriter::new(spiller, keys)?;
+        while let Some(Reverse((k, i, mut values))) = heap.pop() {
+            if let Some((next, more)) = readers[i].next().transpose()? {
+                heap.push(Reverse((next, i, more)));
+            }
+            // The values for the same key in the runs after this one.
+            let mut count = usize::decode(&mut &values[..]);
+            let mut rest = Vec::new();
+            while let Some(Reverse((other, _, _))) = heap.peek() {
+                if *other != k {
+                    break;
+                }
+                let Reverse((_, j, more)) = heap.pop().unwrap();
+                if let Some((next, again)) = readers[j].next().transpose()? {
+                    heap.push(Reverse((next, j, again)));
+                }
+                let mut buf = &more[..];
+                count += usize::decode(&mut buf);
+                rest.extend_from_slice(buf);
+            }
+            if !rest.is_empty() {
+                let mut merged = Vec::with_capacity(values.len() + rest.len());
+                count.encode(&mut merged);
+                merged.extend_from_slice(&values[mem::size_of::<usize>()..]);
+                merged.extend_from_slice(&rest);
+                values = merged;
+            }
+            writer.push(k, &values)?;
+        }
+        let merged = writer.finish(spiller)?;
+        drop(readers);
+        self.runs = vec![merged];
+        spiller.stats.borrow_mut().merges += 1;
+        Ok(())
+    }
+}
+
+// A temporary file, which is deleted once it's dropped.
+struct TempPath(PathBuf);
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+// A sorted run of keys and their encoded values, in a temporary file. Only
+// the first key of each block and a Bloom filter of the keys are kept in
+// memory.
+struct Run<K> {
+    path: TempPath,
+    file: RefCell<File>,
+    // The first key in each block, and where the block starts.
+    blocks: Vec<(K, u64)>,
+    len: u64,
+    bloom: Bloom,
+    keys: usize,
+}
+
+impl<K> Run<K>
+where
+    K: Ord + Hash + Spill,
+{
+    fn read(&self, start: u64, end: u64) -> io::Result<Vec<u8>> {
+        let mut file = self.file.borrow_mut();
+        let mut buf = vec![0; (end - start) as usize];
+        file.seek(SeekFrom::Start(start))
+            .and_then(|_| file.read_exact(&mut buf))
+            .map_err(|e| annotate(e, "read", &self.path.0))?;
+        Ok(buf)
+    }
+
+    fn block(&self, i: usize) -> io::Result<Vec<u8>> {
+        let end = self.blocks.get(i + 1).map_or(self.len, |(_, start)| *start);
+        self.read(self.blocks[i].1, end)
+    }
+
+    // The encoded values for a key, if the run has it.
+    fn find(&self, k: &K) -> io::Result<Option<Vec<u8>>> {
+        if !self.bloom.contains(k) {
+            return Ok(None);
+        }
+        let i = match self.blocks.binary_search_by(|(first, _)| first.cmp(k)) {
+            Ok(i) => i,
+            Err(0) => return Ok(None),
+            Err(i) => i - 1,
+        };
+        let block = self.block(i)?;
+        let mut buf = &block[..];
+        while !buf.is_empty() {
+            let key = K::decode(&mut buf);
+            let len = usize::decode(&mut buf);
+            let values = take(&mut buf, len);
+            if key == *k {
+                return Ok(Some(values.to_vec()));
+            }
+            if key > *k {
+                return Ok(None);
+            }
+        }
+        Ok(None)
+    }
+
+    // Every key and its encoded values, in order.
+    fn entries(&self) -> impl Iterator<Item = io::Result<(K, Vec<u8>)>> + '_ {
+        (0..self.blocks.len()).flat_map(move |i| {
+            let block = match self.block(i) {
+                Ok(block) => block,
+                Err(e) => return vec![Err(e)],
+            };
+            let mut entries = Vec::new();
+            let mut buf = &block[..];
+            while !buf.is_empty() {
+                let key = K::decode(&mut buf);
+                let len = usize::decode(&mut buf);
+                entries.push(Ok((key, take(&mut buf, len).to_vec())));
+            }
+            entries
+        })
+    }
+}
+
+struct RunWriter<K> {
+    path: TempPath,
+    out: BufWriter<File>,
+    blocks: Vec<(K, u64)>,
+    len: u64,
+    block_len: usize,
+    bloom: Bloom,
+    keys: usize,
+}
+
+impl<K> RunWriter<K>
+where
+    K: Hash + Spill,
+{
+    fn new(spiller: &Spiller, keys: usize) -> io::Result<Self> {
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        let path = spiller.dir.join(format!(
+            "babyflow-{}-{}.run",
+            std::process::id(),
+            RUNS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|e| annotate(e, "create", &path))?;
+        Ok(RunWriter {
+            path: TempPath(path),
+            out: BufWriter::new(file),
+            blocks: Vec::new(),
+            len: 0,
+            block_len: BLOCK,
+            bloom: Bloom::new(keys),
+            keys: 0,
+        })
+    }
+
+    // Writes out the next key, which has to come after the last one, and its
+    // encoded values.
+    fn push(&mut self, k: K, values: &[u8]) -> io::Result<()> {
+        let mut buf = Vec::new();
+        k.encode(&mut buf);
+        values.len().encode(&mut buf);
+        buf.extend_from_slice(values);
+        self.bloom.insert(&k);
+        if self.block_len >= BLOCK {
+            self.blocks.push((k, self.len));
+            self.block_len = 0;
+        }
+        self.out
+            .write_all(&buf)
+            .map_err(|e| annotate(e, "write", &self.path.0))?;
+        self.len += buf.len() as u64;
+        self.block_len += buf.len();
+        self.keys += 1;
+        Ok(())
+    }
+
+    fn finish(self, spiller: &Spiller) -> io::Result<Run<K>> {
+        let path = self.path;
+        let file = match self.out.into_inner() {
+            Ok(file) => file,
+            Err(e) => return Err(annotate(e.into_error(), "write", &path.0)),
+        };
+        let mut stats = spiller.stats.borrow_mut();
+        stats.bytes += self.len;
+        stats.runs += 1;
+        Ok(Run {
+            path,
+            file: RefCell::new(file),
+            blocks: self.blocks,
+            len: self.len,
+            bloom: self.bloom,
+            keys: self.keys,
+        })
+    }
+}
+
+// Which keys a run might have, at a byte per key, so looking up a key that
+// isn't there usually doesn't have to read anything.
+struct Bloom(Vec<u64>);
+
+impl Bloom {
+    fn new(keys: usize) -> Self {
+        Bloom(vec![0; keys.div_ceil(8).max(1)])
+    }
+
+    fn bits<K: Hash>(&self, k: &K) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        k.hash(&mut hasher);
+        let h = hasher.finish();
+        let n = self.0.len() as u64 * 64;
+        (0..3u64).map(move |i| (h.wrapping_add(i.wrapping_mul(h.rotate_left(32) | 1)) % n) as usize)
+    }
+
+    fn insert<K: Hash>(&mut self, k: &K) {
+        for bit in self.bits(k).collect::<Vec<_>>() {
+            self.0[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    fn contains<K: Hash>(&self, k: &K) -> bool {
+        self.bits(k)
+            .all(|bit| self.0[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+}
+
+#[test]
+fn test_spill_map() {
+    let spiller = Rc::new(Spiller {
+        limit: 1 << 10,
+        dir: std::env::temp_dir(),
+        stats: RefCell::new(SpillStats::default()),
+        error: RefCell::new(None),
+    });
+    let mut map = SpillMap::new(Some(spiller.clone()));
+    for i in 0..2000i64 {
+        map.insert(i % 300, (i, format!("{}", i))).unwrap();
+    }
+
+    let stats = *spiller.stats.borrow();
+    assert!(stats.runs > MAX_RUNS && stats.merges > 0, "{:?}", stats);
+    assert!(map.runs.len() <= MAX_RUNS);
+    assert!(map.footprint <= 1 << 10);
+
+    // Every value comes back, in the order it went in.
+    for k in [0, 17, 299] {
+        let mut values = Vec::new();
+        map.get(&k, |(i, s): &(i64, String)| {
+            assert_eq!(*s, i.to_string());
+            values.push(*i);
+        })
+        .unwrap();
+        let expected: Vec<_> = (0..2000).filter(|i| i % 300 == k).collect();
+        assert_eq!(values, expected);
+    }
+    assert!(map.contains_key(&5).unwrap());
+    assert!(!map.contains_key(&300).unwrap());
+
+    let paths: Vec<_> = map.runs.iter().map(|run| run.path.0.clone()).collect();
+    drop(map);
+    assert!(paths.iter().all(|path| !path.exists()));
+
+    // A run that can't be written is an error, and leaves everything in
+    // memory.
+    let spiller = Rc::new(Spiller {
+        limit: 1 << 10,
+        dir: std::env::temp_dir().join("babyflow-missing"),
+        stats: RefCell::new(SpillStats::default()),
+        error: RefCell::new(None),
+    });
+    let mut map = SpillMap::new(Some(spiller));
+    let errors = (0..100i64).filter(|i| map.insert(*i, *i).is_err()).count();
+    assert!(errors > 0);
+    let mut values = Vec::new();
+    map.get(&42, |v: &i64| values.push(*v)).unwrap();
+    assert_eq!(values, vec![42]);
+}
@@ -0,0 +1,200 @@
+use std::{
+    any::Any,
+    collections::HashMap,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+};
+
+use super::Query;
+
+type Batch = Box<dyn Any + Send>;
+
+// The shared state between a set of workers: batches of data in flight
+// between them, and enough bookkeeping to tell when all of them are done.
+pub(crate) struct Fabric {
+    peers: usize,
+    state: Mutex<FabricState>,
+    changed: Condvar,
+}
+
+struct FabricState {
+    // (channel, recipient, data).
+    mail: Vec<(usize, usize, Batch)>,
+    idle: usize,
+    done: bool,
+}
+
+impl Fabric {
+    fn new(peers: usize) -> Self {
+        Fabric {
+            peers,
+            state: Mutex::new(FabricState {
+                mail: Vec::new(),
+                idle: 0,
+                done: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub(crate) fn send<T: Send + 'static>(&self, channel: usize, to: usize, batch: Vec<T>) {
+        self.state
+            .lock()
+            .unwrap()
+            .mail
+            .push((channel, to, Box::new(batch)));
+        self.changed.notify_all();
+    }
+
+    // Blocks until someone sends this worker some data, or returns None if
+    // every worker is idle and nothing is in flight.
+    fn receive(&self, index: usize) -> Option<Vec<(usize, Batch)>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let mut mine = Vec::new();
+            let mut i = 0;
+            while i < state.mail.len() {
+                if state.mail[i].1 == index {
+                    let (channel, _, batch) = state.mail.swap_remove(i);
+                    mine.push((channel, batch));
+                } else {
+                    i += 1;
+                }
+            }
+            if !mine.is_empty() {
+                return Some(mine);
+            }
+            if state.done {
+                return None;
+            }
+
+            state.idle += 1;
+            if state.idle == self.peers && state.mail.is_empty() {
+                state.done = true;
+                self.changed.notify_all();
+                return None;
+            }
+            state = self.changed.wait(state).unwrap();
+            state.idle -= 1;
+        }
+    }
+}
+
+pub(crate) struct Worker {
+    pub(crate) index: usize,
+    pub(crate) peers: usize,
+    fabric: Arc<Fabric>,
+    channels: usize,
+    // For each channel, the operator that reads from it and how to hand it
+    // a batch.
+    receivers: HashMap<usize, (usize, Box<dyn FnMut(Batch)>)>,
+}
+
+impl Worker {
+    fn new(index: usize, fabric: Arc<Fabric>) -> Self {
+        Worker {
+            index,
+            peers: fabric.peers,
+            fabric,
+            channels: 0,
+            receivers: HashMap::new(),
+        }
+    }
+
+    // Every worker builds the same graph in the same order, so channels are
+    // identified by the order they were created in.
+    pub(crate) fn channel<T, F>(&mut self, op: usize, mut deliver: F) -> (usize, Arc<Fabric>)
+    where
+        T: Send + 'static,
+        F: FnMut(Vec<T>) + 'static,
+    {
+        let channel = self.channels;
+        self.channels += 1;
+        self.receivers.insert(
+            channel,
+            (
+                op,
+                Box::new(move |batch: Batch| deliver(*batch.downcast::<Vec<T>>().unwrap())),
+            ),
+        );
+        (channel, self.fabric.clone())
+    }
+
+    // Hands any data sent to us to its operators, returning the operators
+    // that need to run, or None when the computation is finished.
+    pub(crate) fn receive(&mut self) -> Option<Vec<usize>> {
+        let mail = self.fabric.receive(self.index)?;
+        Some(
+            mail.into_iter()
+                .map(|(channel, batch)| {
+                    let (op, deliver) = self.receivers.get_mut(&channel).unwrap();
+                    deliver(batch);
+                    *op
+                })
+                .collect(),
+        )
+    }
+}
+
+// Runs `peers` copies of the query built by `f`, each on its own thread. The
+// copies only communicate through Operator::exchange.
+pub fn execute<F>(peers: usize, f: F)
+where
+    F: Fn(&mut Query) + Sync,
+{
+    let fabric = Arc::new(Fabric::new(peers));
+    thread::scope(|s| {
+        for index in 0..peers {
+            let fabric = fabric.clone();
+            let f = &f;
+            s.spawn(move || {
+                let mut q = Query::new();
+                (*q.df).borrow_mut().worker = Some(Worker::new(index, fabric));
+                f(&mut q);
+                while (*q.df).borrow_mut().step() {}
+            });
+        }
+    });
+}
+
+#[test]
+fn test_exchange() {
+    let results = Arc::new(Mutex::new(Vec::new()));
+    let joined = Arc::new(Mutex::new(Vec::new()));
+
+    execute(4, |q| {
+        let (index, peers) = (q.index(), q.peers());
+        let results = results.clone();
+        let joined = joined.clone();
+
+        // Every worker starts out with some of the records for every key, and
+        // after the exchange only has the ones for its own keys.
+        let nums =
+            q.source(move |send| send.give_iterator((0..1000).filter(|i| i % peers == index)));
+        nums.clone()
+            .map(|i| (i % 10, i))
+            .exchange(|(k, _)| *k)
+            .sink(move |(k, _)| results.lock().unwrap().push((k, index)));
+
+        // join_spilling and distinct_spilling exchange their inputs
+        // themselves, so they find every match even when the two sides start
+        // out on different workers.
+        let evens = nums.clone().filter(|i| i % 2 == 0).map(|i| (i % 10, ()));
+        nums.map(|i| (i % 10, ()))
+            .join_spilling(evens)
+            .map(|(k, (), ())| k)
+            .distinct_spilling()
+            .sink(move |k| joined.lock().unwrap().push(k));
+    });
+
+    let mut results = results.lock().unwrap().clone();
+    assert_eq!(results.len(), 1000);
+    results.sort_unstable();
+    results.dedup();
+    let keys: Vec<_> = results.iter().map(|(k, _)| *k).collect();
+    assert_eq!(keys, (0..10).collect::<Vec<_>>());
+
+    let mut joined = joined.lock().unwrap().clone();
+    joined.sort_unstable();
+    assert_eq!(joined, vec![0, 2, 4, 6, 8]);
+}
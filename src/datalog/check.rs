@@ -0,0 +1,292 @@
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    fmt,
+};
+
+use super::{Clause, CmpOp, Ident, Predicate, Program, Term};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    // The program can't be run.
+    Error,
+    // The program can be run, but probably doesn't do what was meant.
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    // The rule the problem is in, if it's in one.
+    pub rule: Option<String>,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(rule) = &self.rule {
+            write!(f, "\n  {}", rule)?;
+        }
+        Ok(())
+    }
+}
+
+impl Program {
+    // Looks for the problems with the program that can be found without
+    // running it.
+    pub fn check(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        let mut error = |message: String, clause: &Clause| {
+            diagnostics.push(Diagnostic {
+                severity: Severity::Error,
+                message,
+                rule: Some(self.show(clause)),
+            })
+        };
+
+        let mut arities: HashMap<Ident, usize> = HashMap::new();
+        for (name, rel) in &self.relations {
+            let aggregate = self.aggregate(*name);
+            for clause in &rel.clauses {
+                for pred in std::iter::once(&clause.head).chain(&clause.body) {
+                    match arities.entry(pred.name) {
+                        Entry::Vacant(e) => {
+                            e.insert(pred.arity());
+                        }
+                        Entry::Occupied(e) if *e.get() != pred.arity() => error(
+                            format!(
+                                "{} is used with {} arguments here, but {} elsewhere",
+                                self.name(pred.name),
+                                pred.arity(),
+                                e.get()
+                            ),
+                            clause,
+                        ),
+                        Entry::Occupied(_) => {}
+                    }
+                }
+
+                if clause.body.iter().any(|pred| pred.aggregate.is_some()) {
+                    error(
+                        "aggregates are only allowed in the heads of rules".to_owned(),
+                        clause,
+                    );
+                }
+                let fact = clause.body.is_empty()
+                    && clause.constraints.is_empty()
+                    && clause.head.variables.is_empty()
+                    && clause.head.aggregate.is_none();
+                if !fact && clause.head.aggregate.map(|(idx, agg, _)| (idx, agg)) != aggregate {
+                    error(
+                        format!(
+                            "every rule for {} must aggregate the same column the same way",
+                            self.name(*name)
+                        ),
+                        clause,
+                    );
+                }
+
+                // Every variable has to be bound by a relation in the body, or
+                // be equal to something computed from ones that are.
+                let mut bound: HashSet<Ident> = clause
+                    .body
+                    .iter()
+                    .filter(|pred| !pred.negated)
+                    .flat_map(|pred| pred.variables.iter().map(|(_, v)| *v))
+                    .collect();
+                loop {
+                    let assigned = clause
+                        .constraints
+                        .iter()
+                        .find_map(|c| match (&c.lhs, &c.rhs) {
+                            (Term::Var(v), t) | (t, Term::Var(v))
+                                if c.op == CmpOp::Eq
+                                    && !bound.contains(v)
+                                    && vars(t).iter().all(|v| bound.contains(v)) =>
+                            {
+                                Some(*v)
+                            }
+                            _ => None,
+                        });
+                    match assigned {
+                        Some(v) => bound.insert(v),
+                        None => break,
+                    };
+                }
+                let mut unbound = Vec::new();
+                let head_vars = clause.head.variables.iter().map(|(_, v)| *v);
+                let agg_var = clause.head.aggregate.iter().map(|(_, _, v)| *v);
+                for v in head_vars.chain(agg_var) {
+                    unbound.push((v, "the head".to_owned()));
+                }
+                for pred in clause.body.iter().filter(|pred| pred.negated) {
+                    for (_, v) in &pred.variables {
+                        unbound.push((*v, format!("!{}", self.name(pred.name))));
+                    }
+                }
+                for c in &clause.constraints {
+                    for v in vars(&c.lhs).into_iter().chain(vars(&c.rhs)) {
+                        unbound.push((v, "a constraint".to_owned()));
+                    }
+                }
+                let mut reported = HashSet::new();
+                for (v, place) in unbound {
+                    if !bound.contains(&v) && reported.insert(v) {
+                        error(
+                            format!(
+                                "variable {} in {} isn't bound by the rule's body",
+                                self.name(v),
+                                place
+                            ),
+                            clause,
+                        );
+                    }
+                }
+            }
+        }
+
+        for (message, clause) in self.stratify().1 {
+            diagnostics.push(Diagnostic {
+                severity: Severity::Error,
+                message,
+                rule: Some(self.show(clause)),
+            });
+        }
+
+        // Relations that have no facts or rules are always empty, unless
+        // they're given facts later through an Instance.
+        let mut unknown = HashSet::new();
+        for rel in self.relations.values() {
+            for clause in &rel.clauses {
+                for pred in &clause.body {
+                    if !self.relations.contains_key(&pred.name) && unknown.insert(pred.name) {
+                        diagnostics.push(Diagnostic {
+                            severity: Severity::Warning,
+                            message: format!("{} has no facts or rules", self.name(pred.name)),
+                            rule: Some(self.show(clause)),
+                        });
+                    }
+                }
+            }
+        }
+
+        // The relations which could ever have something in them.
+        let mut nonempty: HashSet<Ident> = HashSet::new();
+        loop {
+            let before = nonempty.len();
+            for (name, rel) in &self.relations {
+                if !rel.facts.is_empty()
+                    || !rel.inputs.is_empty()
+                    || rel
+                        .clauses
+                        .iter()
+                        .any(|clause| positive(clause).all(|pred| nonempty.contains(&pred.name)))
+                {
+                    nonempty.insert(*name);
+                }
+            }
+            if nonempty.len() == before {
+                break;
+            }
+        }
+        for rel in self.relations.values() {
+            for clause in &rel.clauses {
+                if let Some(pred) = positive(clause).find(|pred| !nonempty.contains(&pred.name)) {
+                    diagnostics.push(Diagnostic {
+                        severity: Severity::Warning,
+                        message: format!(
+                            "this rule never derives anything, since {} is always empty",
+                            self.name(pred.name)
+                        ),
+                        rule: Some(self.show(clause)),
+                    });
+                }
+            }
+        }
+
+        diagnostics.extend(self.type_check());
+        diagnostics
+    }
+
+    // Writes out a clause the way it would have been parsed.
+    pub(super) fn show(&self, clause: &Clause) -> String {
+        let mut out = self.show_predicate(&clause.head);
+        let mut sep = " <- ";
+        for pred in &clause.body {
+            out.push_str(sep);
+            if pred.negated {
+                out.push('!');
+            }
+            out.push_str(&self.show_predicate(pred));
+            sep = ", ";
+        }
+        for c in &clause.constraints {
+            out.push_str(&format!(
+                "{}{} {} {}",
+                sep,
+                self.show_term(&c.lhs, false),
+                c.op,
+                self.show_term(&c.rhs, false)
+            ));
+            sep = ", ";
+        }
+        out.push('.');
+        out
+    }
+
+    pub(super) fn show_predicate(&self, pred: &Predicate) -> String {
+        let mut args = vec![String::new(); pred.arity()];
+        for (idx, d) in &pred.constants {
+            args[*idx] = d.to_string();
+        }
+        for (idx, v) in &pred.variables {
+            args[*idx] = self.name(*v).to_owned();
+        }
+        if let Some((idx, agg, v)) = pred.aggregate {
+            args[idx] = format!("{}({})", agg.name(), self.name(v));
+        }
+        format!("{}({})", self.name(pred.name), args.join(", "))
+    }
+
+    fn show_term(&self, t: &Term, nested: bool) -> String {
+        match t {
+            Term::Var(v) => self.name(*v).to_owned(),
+            Term::Datum(d) => d.to_string(),
+            Term::Arith(op, l, r) if nested => {
+                format!(
+                    "({} {} {})",
+                    self.show_term(l, true),
+                    op,
+                    self.show_term(r, true)
+                )
+            }
+            Term::Arith(op, l, r) => {
+                format!(
+                    "{} {} {}",
+                    self.show_term(l, true),
+                    op,
+                    self.show_term(r, true)
+                )
+            }
+        }
+    }
+}
+
+fn vars(t: &Term) -> Vec<Ident> {
+    let mut out = Vec::new();
+    t.vars(&mut out);
+    out
+}
+
+fn positive(clause: &Clause) -> impl Iterator<Item = &Predicate> {
+    clause.body.iter().filter(|pred| !pred.negated)
+}
@@ -0,0 +1,278 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+use anyhow::bail;
+
+use super::{Datum, Ident, Program, Row, Type};
+
+// A file a relation's facts are read from when the program is run.
+#[derive(Debug, Clone)]
+pub(super) struct Input {
+    pub path: PathBuf,
+    pub delimiter: char,
+}
+
+impl Input {
+    // Without a delimiter, .tsv files are split on tabs and everything else
+    // on commas.
+    pub fn new(path: PathBuf, delimiter: Option<char>) -> Self {
+        let delimiter = delimiter.unwrap_or_else(|| {
+            if path.extension().is_some_and(|ext| ext == "tsv") {
+                '\t'
+            } else {
+                ','
+            }
+        });
+        Input { path, delimiter }
+    }
+}
+
+impl Program {
+    // Reads facts for a relation from a file with one tuple per line, like
+    // `1,2`. Fields can be quoted like in CSV, to include the delimiter.
+    pub fn load_csv(
+        &mut self,
+        rel: &str,
+        path: impl AsRef<Path>,
+        delimiter: char,
+    ) -> anyhow::Result<()> {
+        let name = self.intern(rel);
+        let rows = read(path.as_ref(), delimiter, self.columns(name))?;
+        self.add_facts(name, rows, &path.as_ref().display().to_string())
+    }
+
+    // Reads every file given to an .input directive.
+    pub(super) fn load_inputs(&mut self) -> anyhow::Result<()> {
+        let inputs: Vec<_> = self
+            .relations
+            .iter_mut()
+            .flat_map(|(name, rel)| rel.inputs.drain(..).map(move |input| (*name, input)))
+            .collect();
+        for (name, input) in inputs {
+            let rows = read(&input.path, input.delimiter, self.columns(name))?;
+            self.add_facts(name, rows, &input.path.display().to_string())?;
+        }
+        Ok(())
+    }
+
+    fn add_facts(&mut self, name: Ident, rows: Vec<Row>, path: &str) -> anyhow::Result<()> {
+        if let (Some(row), Some(arity)) = (rows.first(), self.arity(name)) {
+            if row.len() != arity {
+                bail!(
+                    "{}: {} has {} arguments, but there are {} fields",
+                    path,
+                    self.name(name),
+                    arity,
+                    row.len()
+                );
+            }
+        }
+        self.relations.entry(name).or_default().facts.extend(rows);
+        Ok(())
+    }
+
+    // How many arguments the relation is declared or used with, if it's used
+    // anywhere.
+    fn arity(&self, name: Ident) -> Option<usize> {
+        if let Some(columns) = self.columns(name) {
+            return Some(columns.len());
+        }
+        let rel = self.relations.get(&name);
+        if let Some(row) = rel.and_then(|rel| rel.facts.first()) {
+            return Some(row.len());
+        }
+        self.relations
+            .values()
+            .flat_map(|rel| &rel.clauses)
+            .flat_map(|clause| std::iter::once(&clause.head).chain(&clause.body))
+            .find(|pred| pred.name == name)
+            .map(|pred| pred.arity())
+    }
+}
+
+// Reads the tuples in a file. Columns of declared relations are parsed as
+// their declared types. Otherwise a column is made of integers if every value
+// in it is one, and of atoms if not.
+fn read(
+    path: &Path,
+    delimiter: char,
+    columns: Option<&[(String, Type)]>,
+) -> anyhow::Result<Vec<Row>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => bail!("can't open {}: {}", path.display(), e),
+    };
+    let mut rows: Vec<Row> = Vec::new();
+    // Which columns have had something other than an integer in them.
+    let mut atoms: Vec<bool> = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => bail!("can't read {}: {}", path.display(), e),
+        };
+        let line = line.strip_suffix('\r').unwrap_or(&line);
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields = match split(line, delimiter) {
+            Ok(fields) => fields,
+            Err(e) => bail!("{}:{}: {}", path.display(), i + 1, e),
+        };
+        if let Some(first) = rows.first() {
+            if first.len() != fields.len() {
+                bail!(
+                    "{}:{}: expected {} fields but found {}",
+                    path.display(),
+                    i + 1,
+                    first.len(),
+                    fields.len()
+                );
+            }
+        }
+
+        if let Some(columns) = columns {
+            if columns.len() != fields.len() {
+                bail!(
+                    "{}:{}: expected {} fields but found {}",
+                    path.display(),
+                    i + 1,
+                    columns.len(),
+                    fields.len()
+                );
+            }
+            let mut row = Row::new();
+            for (field, (col, t)) in fields.into_iter().zip(columns) {
+                match t.parse(&field) {
+                    Some(d) => row.push(d),
+                    None => bail!(
+                        "{}:{}: expected {} for {} but found {}",
+                        path.display(),
+                        i + 1,
+                        t,
+                        col,
+                        field
+                    ),
+                }
+            }
+            rows.push(row);
+            continue;
+        }
+
+        atoms.resize(fields.len(), false);
+        let row = fields
+            .into_iter()
+            .zip(atoms.iter_mut())
+            .map(|(field, atom)| match field.parse::<i64>() {
+                // Only if it'd be written the same way, so nothing is lost if
+                // the column turns out to be atoms.
+                Ok(n) if !*atom && n.to_string() == field => Datum::Int(n),
+                _ => {
+                    *atom = true;
+                    Datum::atom(&field)
+                }
+            })
+            .collect();
+        rows.push(row);
+    }
+
+    for row in &mut rows {
+        for (d, atom) in row.iter_mut().zip(&atoms) {
+            if let (Datum::Int(n), true) = (&*d, atom) {
+                *d = Datum::atom(&n.to_string());
+            }
+        }
+    }
+    Ok(rows)
+}
+
+// Splits a line into its fields. Unquoted fields have the whitespace around
+// them trimmed, and quotes in quoted fields are doubled.
+fn split(line: &str, delimiter: char) -> Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars
+            .peek()
+            .is_some_and(|c| *c != delimiter && c.is_whitespace())
+        {
+            chars.next();
+        }
+        let mut field = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    Some('"') => break,
+                    Some(c) => field.push(c),
+                    None => return Err("unterminated quoted field".to_owned()),
+                }
+            }
+            while chars
+                .peek()
+                .is_some_and(|c| *c != delimiter && c.is_whitespace())
+            {
+                chars.next();
+            }
+            if chars.peek().is_some_and(|c| *c != delimiter) {
+                return Err(format!("expected {:?} after a quoted field", delimiter));
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| *c != delimiter) {
+                field.push(c);
+            }
+            field.truncate(field.trim_end().len());
+        }
+        fields.push(field);
+        if chars.next().is_none() {
+            return Ok(fields);
+        }
+    }
+}
+
+#[test]
+fn test_load_csv() {
+    let mut p = Program::build("reachable(X, Y) <- edge(X, Y).").unwrap();
+    p.load_csv("edge", "src/testdata/inputs/edge.csv", ',')
+        .unwrap();
+    let mut rows = p.clone().render("reachable").unwrap();
+    rows.sort();
+    assert_eq!(
+        rows,
+        vec![
+            vec![Datum::Int(1), Datum::Int(2)],
+            vec![Datum::Int(2), Datum::Int(3)],
+            vec![Datum::Int(3), Datum::Int(4)],
+        ]
+    );
+    assert!(p
+        .load_csv("edge", "src/testdata/inputs/ragged.csv", ',')
+        .is_err());
+}
+
+#[test]
+fn test_split() {
+    assert_eq!(split("1,2", ','), Ok(vec!["1".to_owned(), "2".to_owned()]));
+    assert_eq!(
+        split(" a b ,\"c,\"\"d\"\"\" ,", ','),
+        Ok(vec!["a b".to_owned(), "c,\"d\"".to_owned(), "".to_owned()])
+    );
+    assert_eq!(
+        split("1\t 2\t", '\t'),
+        Ok(vec!["1".to_owned(), "2".to_owned(), "".to_owned()])
+    );
+    assert_eq!(
+        split("\"a\"b", ','),
+        Err("expected ',' after a quoted field".to_owned())
+    );
+    assert_eq!(
+        split("\"a", ','),
+        Err("unterminated quoted field".to_owned())
+    );
+}
@@ -1,14 +1,443 @@
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+use std::{
+    cmp::Ordering,
+    convert::TryFrom,
+    hash::{Hash, Hasher},
+};
+
+use super::Symbol;
+use crate::babyflow::{Diff, Spill};
+
+#[derive(Debug, Clone, Copy)]
 pub enum Datum {
     Int(i64),
-    Atom(String),
+    UInt(u64),
+    Float(f64),
+    Bool(bool),
+    // Both bare atoms, like `alice`, and quoted strings, like `"Alice B."`.
+    Atom(Symbol),
+}
+
+impl Datum {
+    pub fn atom(s: &str) -> Self {
+        Datum::Atom(Symbol::new(s))
+    }
+
+    // The value of a number as a float, or NaN if it isn't one.
+    fn float(&self) -> f64 {
+        match self {
+            Datum::Int(i) => *i as f64,
+            Datum::UInt(u) => *u as f64,
+            Datum::Float(x) => *x,
+            _ => f64::NAN,
+        }
+    }
+
+    // An integer of either kind, widened so sums of them don't overflow.
+    fn int(&self) -> i128 {
+        match self {
+            Datum::Int(i) => *i as i128,
+            Datum::UInt(u) => *u as i128,
+            _ => 0,
+        }
+    }
+
+    // Orders the variants, for comparing data of different types.
+    fn rank(&self) -> u8 {
+        match self {
+            Datum::Int(_) => 0,
+            Datum::UInt(_) => 1,
+            Datum::Float(_) => 2,
+            Datum::Bool(_) => 3,
+            Datum::Atom(_) => 4,
+        }
+    }
+}
+
+// Floats are compared by their total order, so that they can be used as keys
+// like everything else.
+impl PartialEq for Datum {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Datum {}
+
+impl PartialOrd for Datum {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Datum {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Datum::Int(l), Datum::Int(r)) => l.cmp(r),
+            (Datum::UInt(l), Datum::UInt(r)) => l.cmp(r),
+            (Datum::Float(l), Datum::Float(r)) => l.total_cmp(r),
+            (Datum::Bool(l), Datum::Bool(r)) => l.cmp(r),
+            (Datum::Atom(l), Datum::Atom(r)) => l.cmp(r),
+            (l, r) => l.rank().cmp(&r.rank()),
+        }
+    }
+}
+
+impl Hash for Datum {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.rank().hash(state);
+        match self {
+            Datum::Int(i) => i.hash(state),
+            Datum::UInt(u) => u.hash(state),
+            Datum::Float(x) => x.to_bits().hash(state),
+            Datum::Bool(b) => b.hash(state),
+            Datum::Atom(s) => s.hash(state),
+        }
+    }
 }
 
+// Atoms are written out as their text, and interned again when they're read
+// back.
+impl Spill for Datum {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Datum::Int(i) => {
+                buf.push(0);
+                i.encode(buf);
+            }
+            Datum::UInt(u) => {
+                buf.push(1);
+                u.encode(buf);
+            }
+            Datum::Float(x) => {
+                buf.push(2);
+                x.encode(buf);
+            }
+            Datum::Bool(b) => {
+                buf.push(3);
+                b.encode(buf);
+            }
+            Datum::Atom(s) => {
+                buf.push(4);
+                s.as_str().to_owned().encode(buf);
+            }
+        }
+    }
+
+    fn decode(buf: &mut &[u8]) -> Self {
+        match u8::decode(buf) {
+            0 => Datum::Int(i64::decode(buf)),
+            1 => Datum::UInt(u64::decode(buf)),
+            2 => Datum::Float(f64::decode(buf)),
+            3 => Datum::Bool(bool::decode(buf)),
+            4 => Datum::atom(&String::decode(buf)),
+            tag => panic!("can't decode a datum with tag {}", tag),
+        }
+    }
+}
+
+// Written the way it would be in a program, so atoms that couldn't be
+// written bare are quoted.
 impl std::fmt::Display for Datum {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Datum::Int(i) => write!(f, "{}", i),
-            Datum::Atom(s) => write!(f, "{}", s),
+            Datum::UInt(u) => write!(f, "{}", u),
+            Datum::Float(x) => write!(f, "{:?}", x),
+            Datum::Bool(b) => write!(f, "{}", b),
+            Datum::Atom(s) if bare(s.as_str()) => write!(f, "{}", s),
+            Datum::Atom(s) => quote(f, s.as_str()),
+        }
+    }
+}
+
+// Writes a datum from a string column, where even atoms that could be
+// written bare are quoted so that it reads back as a string.
+pub struct Quoted<'a>(pub &'a Datum);
+
+impl std::fmt::Display for Quoted<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Datum::Atom(s) => quote(f, s.as_str()),
+            d => write!(f, "{}", d),
+        }
+    }
+}
+
+fn quote(f: &mut std::fmt::Formatter<'_>, s: &str) -> std::fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\t' => write!(f, "\\t")?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+// Whether an atom can be written without quotes.
+fn bare(s: &str) -> bool {
+    s.starts_with(char::is_lowercase)
+        && s.chars().all(char::is_alphanumeric)
+        && s != "true"
+        && s != "false"
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Type {
+    Int,
+    UInt,
+    Float,
+    Bool,
+    // Atoms and quoted strings.
+    String,
+}
+
+impl Type {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "int" => Some(Type::Int),
+            "uint" => Some(Type::UInt),
+            "float" => Some(Type::Float),
+            "bool" => Some(Type::Bool),
+            "string" => Some(Type::String),
+            _ => None,
+        }
+    }
+
+    pub fn of(d: &Datum) -> Self {
+        match d {
+            Datum::Int(_) => Type::Int,
+            Datum::UInt(_) => Type::UInt,
+            Datum::Float(_) => Type::Float,
+            Datum::Bool(_) => Type::Bool,
+            Datum::Atom(_) => Type::String,
+        }
+    }
+
+    pub fn numeric(&self) -> bool {
+        matches!(self, Type::Int | Type::UInt | Type::Float)
+    }
+
+    // Converts a datum to this type, if it can be without losing anything.
+    // That's only ever needed for integer literals, which can be used as any
+    // kind of number.
+    pub fn coerce(&self, d: &Datum) -> Option<Datum> {
+        match (self, d) {
+            (Type::UInt, Datum::Int(i)) if *i >= 0 => Some(Datum::UInt(*i as u64)),
+            (Type::Float, Datum::Int(i)) => Some(Datum::Float(*i as f64)),
+            (t, d) if Type::of(d) == *t => Some(*d),
+            _ => None,
+        }
+    }
+
+    // Reads a field from a file of this type.
+    pub fn parse(&self, s: &str) -> Option<Datum> {
+        match self {
+            Type::Int => s.parse().ok().map(Datum::Int),
+            Type::UInt => s.parse().ok().map(Datum::UInt),
+            Type::Float => s.parse().ok().map(Datum::Float),
+            Type::Bool => s.parse().ok().map(Datum::Bool),
+            Type::String => Some(Datum::atom(s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Type::Int => "int",
+            Type::UInt => "uint",
+            Type::Float => "float",
+            Type::Bool => "bool",
+            Type::String => "string",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregate {
+    Count,
+    Sum,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "count" => Some(Aggregate::Count),
+            "sum" => Some(Aggregate::Sum),
+            "min" => Some(Aggregate::Min),
+            "max" => Some(Aggregate::Max),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aggregate::Count => "count",
+            Aggregate::Sum => "sum",
+            Aggregate::Min => "min",
+            Aggregate::Max => "max",
+        }
+    }
+
+    // Whether the aggregate only ever moves in one direction as values are
+    // added, regardless of the order they arrive in, so that it's safe to use
+    // in a recursive rule.
+    pub fn monotone(&self) -> bool {
+        matches!(self, Aggregate::Min | Aggregate::Max)
+    }
+
+    // The aggregate of a group of values, each given with how many times it
+    // appears in the group. Only numbers count towards sums. A sum of ints or
+    // of uints is worked out exactly and is only a float if it doesn't fit,
+    // and any other sum of numbers is a float, added up in sorted order so
+    // it comes out the same however the group was put together.
+    pub fn apply(&self, values: &[(Datum, Diff)]) -> Option<Datum> {
+        match self {
+            Aggregate::Count => Some(Datum::Int(values.iter().map(|(_, n)| *n as i64).sum())),
+            Aggregate::Min => values.iter().map(|(d, _)| *d).min(),
+            Aggregate::Max => values.iter().map(|(d, _)| *d).max(),
+            Aggregate::Sum => {
+                let mut nums: Vec<_> = values
+                    .iter()
+                    .filter(|(d, _)| Type::of(d).numeric())
+                    .copied()
+                    .collect();
+                nums.sort_unstable();
+                let ty = Type::of(&nums.first()?.0);
+                if nums.iter().any(|(d, _)| Type::of(d) != ty) {
+                    return Some(Datum::Float(
+                        nums.iter().map(|(d, n)| d.float() * *n as f64).sum(),
+                    ));
+                }
+                Some(match ty {
+                    Type::Int => {
+                        let sum: i128 = nums.iter().map(|(d, n)| d.int() * *n as i128).sum();
+                        i64::try_from(sum).map_or(Datum::Float(sum as f64), Datum::Int)
+                    }
+                    Type::UInt => {
+                        let sum: i128 = nums.iter().map(|(d, n)| d.int() * *n as i128).sum();
+                        u64::try_from(sum).map_or(Datum::Float(sum as f64), Datum::UInt)
+                    }
+                    _ => Datum::Float(nums.iter().map(|(d, n)| d.float() * *n as f64).sum()),
+                })
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+impl std::fmt::Display for ArithOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            ArithOp::Add => "+",
+            ArithOp::Sub => "-",
+            ArithOp::Mul => "*",
+            ArithOp::Div => "/",
+            ArithOp::Mod => "%",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl ArithOp {
+    // Arithmetic is only done on two numbers of the same type. None if
+    // they're not, or on overflow or division by zero.
+    pub fn apply(&self, l: &Datum, r: &Datum) -> Option<Datum> {
+        match (l, r) {
+            (Datum::Int(l), Datum::Int(r)) => {
+                let (l, r) = (*l, *r);
+                match self {
+                    ArithOp::Add => l.checked_add(r),
+                    ArithOp::Sub => l.checked_sub(r),
+                    ArithOp::Mul => l.checked_mul(r),
+                    ArithOp::Div => l.checked_div(r),
+                    ArithOp::Mod => l.checked_rem(r),
+                }
+                .map(Datum::Int)
+            }
+            (Datum::UInt(l), Datum::UInt(r)) => {
+                let (l, r) = (*l, *r);
+                match self {
+                    ArithOp::Add => l.checked_add(r),
+                    ArithOp::Sub => l.checked_sub(r),
+                    ArithOp::Mul => l.checked_mul(r),
+                    ArithOp::Div => l.checked_div(r),
+                    ArithOp::Mod => l.checked_rem(r),
+                }
+                .map(Datum::UInt)
+            }
+            (Datum::Float(l), Datum::Float(r)) => {
+                let x = match self {
+                    ArithOp::Add => l + r,
+                    ArithOp::Sub => l - r,
+                    ArithOp::Mul => l * r,
+                    ArithOp::Div => l / r,
+                    ArithOp::Mod => l % r,
+                };
+                Some(Datum::Float(x)).filter(|_| x.is_finite())
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl std::fmt::Display for CmpOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            CmpOp::Eq => "=",
+            CmpOp::Ne => "!=",
+            CmpOp::Lt => "<",
+            CmpOp::Le => "<=",
+            CmpOp::Gt => ">",
+            CmpOp::Ge => ">=",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl CmpOp {
+    // Anything can be tested for equality, but only numbers of the same type
+    // are ordered.
+    pub fn apply(&self, l: &Datum, r: &Datum) -> bool {
+        match self {
+            CmpOp::Eq => return l == r,
+            CmpOp::Ne => return l != r,
+            _ => {}
+        }
+        if Type::of(l) != Type::of(r) || !Type::of(l).numeric() {
+            return false;
+        }
+        let ord = l.cmp(r);
+        match self {
+            CmpOp::Lt => ord.is_lt(),
+            CmpOp::Le => ord.is_le(),
+            CmpOp::Gt => ord.is_gt(),
+            _ => ord.is_ge(),
         }
     }
 }
@@ -17,4 +446,8 @@ impl std::fmt::Display for Datum {
 pub enum Expr {
     Var(String),
     Datum(Datum),
+    // Only allowed in the head of a rule.
+    Aggregate(Aggregate, String),
+    // Only allowed in constraints.
+    Arith(ArithOp, Box<Expr>, Box<Expr>),
 }
@@ -0,0 +1,369 @@
+use std::collections::{BTreeMap, HashSet};
+
+use anyhow::bail;
+
+use super::{
+    parser::parse_query, Clause, CmpOp, Constraint, Datum, Ident, Predicate, Program, Severity,
+    Term,
+};
+
+// The relation a query's answers are put in. It can't be written in a
+// program, so it can't clash with anything.
+const QUERY: &str = "?";
+
+impl Program {
+    // Finds every way to satisfy a rule body, like `reachable(1, X), X > 3`.
+    // Each answer has the values of the query's variables, in the order they
+    // first appear.
+    pub fn query(&self, q: &str) -> anyhow::Result<Vec<Vec<Datum>>> {
+        answer(self.magic(q)?)
+    }
+
+    // Finds the tuples of a relation which have the given values for the
+    // arguments that aren't None.
+    pub fn query_pred(&self, rel: &str, args: &[Option<Datum>]) -> anyhow::Result<Vec<Vec<Datum>>> {
+        let mut p = self.clone();
+        let root = p.pred_clause(rel, args)?;
+        answer(p.rewrite(root)?)
+    }
+
+    // The program rewritten to answer a query.
+    pub(super) fn magic(&self, q: &str) -> anyhow::Result<Program> {
+        let mut p = self.clone();
+        let root = p.query_clause(q)?;
+        p.rewrite(root)
+    }
+
+    fn query_clause(&mut self, q: &str) -> anyhow::Result<Clause> {
+        let (body, constraints) = self.intern_body(&parse_query(q)?)?;
+        let mut vars = Vec::new();
+        for pred in body.iter().filter(|pred| !pred.negated) {
+            vars.extend(pred.variables.iter().map(|(_, v)| *v));
+        }
+        for c in &constraints {
+            c.lhs.vars(&mut vars);
+            c.rhs.vars(&mut vars);
+        }
+        let mut seen = HashSet::new();
+        vars.retain(|v| seen.insert(*v));
+
+        Ok(Clause {
+            head: Predicate {
+                name: self.intern(QUERY),
+                constants: Vec::new(),
+                variables: vars.into_iter().enumerate().collect(),
+                aggregate: None,
+                negated: false,
+            },
+            body,
+            constraints,
+        })
+    }
+
+    fn pred_clause(&mut self, rel: &str, args: &[Option<Datum>]) -> anyhow::Result<Clause> {
+        let name = match self.idents.get(rel) {
+            Some(id) if self.relation_names().contains(id) => *id,
+            _ => bail!("unknown relation {}", rel),
+        };
+        let mut pred = Predicate {
+            name,
+            constants: Vec::new(),
+            variables: Vec::new(),
+            aggregate: None,
+            negated: false,
+        };
+        for (i, arg) in args.iter().enumerate() {
+            match arg {
+                Some(d) => pred.constants.push((i, *d)),
+                None => pred.variables.push((i, self.intern(&format!("_{}", i)))),
+            }
+        }
+        Ok(Clause {
+            head: Predicate {
+                name: self.intern(QUERY),
+                ..pred.clone()
+            },
+            body: vec![pred],
+            constraints: Vec::new(),
+        })
+    }
+
+    // Rewrites the program so that it only computes what's needed to answer
+    // the query in `root`, using magic sets.
+    //
+    // A relation used with some of its arguments known gets a copy of its
+    // rules which only derive tuples with those arguments, written
+    // `rel@bf` for the first argument being bound and the second free. Which
+    // values are asked for are collected in `magic@rel@bf`, whose rules are
+    // made from the bodies of the rules using `rel@bf`, up to where it's
+    // used, so that the bindings get passed down through the rules from left
+    // to right.
+    //
+    // Relations used with nothing known, negated relations, and aggregated
+    // relations are computed in full, as they would be anyway. That keeps the
+    // rewritten program stratified: the new relations only ever depend
+    // positively on each other.
+    fn rewrite(mut self, root: Clause) -> anyhow::Result<Program> {
+        let head = root.head.clone();
+        self.add_clause(root.clone());
+        if let Some(d) = self
+            .check()
+            .into_iter()
+            .find(|d| d.severity == Severity::Error)
+        {
+            bail!("{}", d);
+        }
+        self.strata()?;
+        self.coerce()?;
+
+        let mut r = Rewriter {
+            out: Program {
+                idents: self.idents.clone(),
+                relations: BTreeMap::new(),
+                multiway_join: self.multiway_join,
+                memory_limit: self.memory_limit,
+            },
+            program: &self,
+            todo: Vec::new(),
+            seen: HashSet::new(),
+            full: HashSet::new(),
+        };
+        r.rewrite(head, &root, None);
+        while let Some((name, bound)) = r.todo.pop() {
+            let adorned = r.adorned(name, &bound);
+            let magic = r.magic(name, &bound);
+            for clause in &self.relations[&name].clauses {
+                let guard = restrict(&clause.head, &bound, magic);
+                let head = Predicate {
+                    name: adorned,
+                    ..clause.head.clone()
+                };
+                r.rewrite(head, clause, Some(guard));
+            }
+        }
+
+        // Bring along every relation that's computed in full, and everything
+        // it depends on.
+        let mut stack: Vec<_> = r.full.drain().collect();
+        let mut seen = HashSet::new();
+        while let Some(name) = stack.pop() {
+            if !seen.insert(name) {
+                continue;
+            }
+            if let Some(rel) = self.relations.get(&name) {
+                for clause in &rel.clauses {
+                    stack.extend(clause.body.iter().map(|pred| pred.name));
+                }
+                r.out.relations.insert(name, rel.clone());
+            }
+        }
+        Ok(r.out)
+    }
+}
+
+struct Rewriter<'a> {
+    program: &'a Program,
+    out: Program,
+    // Adorned relations whose rules still need to be written.
+    todo: Vec<(Ident, Vec<bool>)>,
+    seen: HashSet<(Ident, Vec<bool>)>,
+    // Relations which are used with nothing known.
+    full: HashSet<Ident>,
+}
+
+impl<'a> Rewriter<'a> {
+    fn adorned(&mut self, name: Ident, bound: &[bool]) -> Ident {
+        if self.seen.insert((name, bound.to_vec())) {
+            self.todo.push((name, bound.to_vec()));
+        }
+        let name = format!("{}@{}", self.program.name(name), adornment(bound));
+        self.out.intern(&name)
+    }
+
+    fn magic(&mut self, name: Ident, bound: &[bool]) -> Ident {
+        let name = format!("magic@{}@{}", self.program.name(name), adornment(bound));
+        self.out.intern(&name)
+    }
+
+    // Whether it's worth specializing a relation's rules: if it has any, and
+    // isn't aggregated. Restricting the body of an aggregate would change
+    // what it aggregates over. Relations with facts loaded from files are
+    // left alone too, rather than copying all of their facts.
+    fn specializable(&self, name: Ident) -> bool {
+        self.program.aggregate(name).is_none()
+            && self.program.relations.get(&name).is_some_and(|rel| {
+                rel.facts.is_empty()
+                    && rel.inputs.is_empty()
+                    && rel
+                        .clauses
+                        .iter()
+                        .any(|c| !c.body.is_empty() || !c.constraints.is_empty())
+            })
+    }
+
+    // Writes out a clause with the given head, guarded by `guard` if there is
+    // one, and with every positive relation in its body that has some of its
+    // arguments bound replaced by its specialized version.
+    fn rewrite(&mut self, head: Predicate, clause: &Clause, guard: Option<Predicate>) {
+        let mut bound: HashSet<Ident> = HashSet::new();
+        let mut body = Vec::new();
+        if let Some(guard) = guard {
+            bound.extend(guard.variables.iter().map(|(_, v)| *v));
+            body.push(guard);
+        }
+        bind(&mut bound, &clause.constraints);
+
+        for pred in &clause.body {
+            if pred.negated {
+                self.full.insert(pred.name);
+                body.push(pred.clone());
+                continue;
+            }
+            let mut args = vec![false; pred.arity()];
+            for (idx, _) in &pred.constants {
+                args[*idx] = true;
+            }
+            for (idx, v) in &pred.variables {
+                args[*idx] = bound.contains(v);
+            }
+
+            if self.specializable(pred.name) && args.contains(&true) {
+                let magic = self.magic(pred.name, &args);
+                let constraints = clause
+                    .constraints
+                    .iter()
+                    .filter(|c| {
+                        let mut vars = Vec::new();
+                        c.lhs.vars(&mut vars);
+                        c.rhs.vars(&mut vars);
+                        vars.iter().all(|v| bound.contains(v))
+                    })
+                    .cloned()
+                    .collect::<Vec<_>>();
+                let head = restrict(pred, &args, magic);
+                // A recursive rule passing its own bindings straight back to
+                // itself doesn't ask for anything new.
+                let trivial = match body.as_slice() {
+                    [guard] => {
+                        constraints.is_empty()
+                            && guard.name == head.name
+                            && guard.constants == head.constants
+                            && guard.variables == head.variables
+                    }
+                    _ => false,
+                };
+                if !trivial {
+                    self.out.add_clause(Clause {
+                        head,
+                        body: body.clone(),
+                        constraints,
+                    });
+                }
+                body.push(Predicate {
+                    name: self.adorned(pred.name, &args),
+                    ..pred.clone()
+                });
+            } else {
+                self.full.insert(pred.name);
+                body.push(pred.clone());
+            }
+
+            bound.extend(pred.variables.iter().map(|(_, v)| *v));
+            bind(&mut bound, &clause.constraints);
+        }
+
+        self.out.add_clause(Clause {
+            head,
+            body,
+            constraints: clause.constraints.clone(),
+        });
+    }
+}
+
+fn answer(p: Program) -> anyhow::Result<Vec<Vec<Datum>>> {
+    let mut rows = p.render(QUERY)?;
+    rows.sort();
+    Ok(rows)
+}
+
+// Like `bf`, for which arguments are bound and which are free.
+fn adornment(bound: &[bool]) -> String {
+    bound.iter().map(|b| if *b { 'b' } else { 'f' }).collect()
+}
+
+// The bound arguments of a predicate, as arguments to `name`.
+fn restrict(pred: &Predicate, bound: &[bool], name: Ident) -> Predicate {
+    let position = |idx: usize| bound[..idx].iter().filter(|b| **b).count();
+    Predicate {
+        name,
+        constants: pred
+            .constants
+            .iter()
+            .filter(|(idx, _)| bound[*idx])
+            .map(|(idx, d)| (position(*idx), *d))
+            .collect(),
+        variables: pred
+            .variables
+            .iter()
+            .filter(|(idx, _)| bound[*idx])
+            .map(|(idx, v)| (position(*idx), *v))
+            .collect(),
+        aggregate: None,
+        negated: false,
+    }
+}
+
+// Adds the variables which are equal to something computed from bound ones.
+pub(super) fn bind(bound: &mut HashSet<Ident>, constraints: &[Constraint]) {
+    loop {
+        let before = bound.len();
+        for c in constraints.iter().filter(|c| c.op == CmpOp::Eq) {
+            for (var, other) in [(&c.lhs, &c.rhs), (&c.rhs, &c.lhs)] {
+                if let Term::Var(v) = var {
+                    let mut vars = Vec::new();
+                    other.vars(&mut vars);
+                    if vars.iter().all(|v| bound.contains(v)) {
+                        bound.insert(*v);
+                    }
+                }
+            }
+        }
+        if bound.len() == before {
+            return;
+        }
+    }
+}
+
+#[test]
+fn test_magic_prunes() {
+    let mut src = String::new();
+    for i in 0..20 {
+        src.push_str(&format!("edge({}, {}).\n", i, i + 1));
+        src.push_str(&format!("edge({}, {}).\n", i + 100, i + 101));
+    }
+    src.push_str("reachable(X, Y) <- edge(X, Y).\n");
+    src.push_str("reachable(X, Z) <- reachable(X, Y), edge(Y, Z).\n");
+    let p = Program::build(&src).unwrap();
+
+    let expected: Vec<_> = (16..=20).map(|i| vec![Datum::Int(i)]).collect();
+    assert_eq!(p.query("reachable(15, X)").unwrap(), expected);
+    let expected: Vec<_> = (16..=20)
+        .map(|i| vec![Datum::Int(15), Datum::Int(i)])
+        .collect();
+    assert_eq!(
+        p.query_pred("reachable", &[Some(Datum::Int(15)), None])
+            .unwrap(),
+        expected
+    );
+
+    // Only the tuples reachable from 15 should be derived, rather than every
+    // pair of connected nodes.
+    let rewritten = p
+        .magic("reachable(15, X)")
+        .unwrap()
+        .instantiate(QUERY)
+        .unwrap();
+    let full = p.instantiate("reachable").unwrap();
+    assert!(rewritten.derivations() < 30);
+    assert!(full.derivations() > 400);
+}
@@ -1,18 +1,42 @@
 use std::{
     cell::RefCell,
-    collections::{BTreeMap, HashMap},
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet},
+    mem,
     rc::Rc,
 };
 
+use anyhow::bail;
+
+mod check;
+mod input;
 mod lang;
+mod magic;
+pub mod output;
 mod parser;
+mod plan;
+pub mod repl;
+mod symbol;
+mod tuple;
+mod types;
 
-pub use lang::{Datum, Expr};
-use parser::parse;
+pub use check::{Diagnostic, Severity};
+use input::Input;
+pub use lang::{Aggregate, ArithOp, CmpOp, Datum, Expr, Type};
+use output::Output;
+use parser::{parse, Directive, Literal};
+pub use parser::{ParseError, ParseErrors};
+use plan::{cyclic, order, Step};
+pub use symbol::Symbol;
+use tuple::Tuple;
 
-use crate::babyflow::{Operator, Query, SendCtx};
+use crate::babyflow::{Arranged, Diff, InputHandle, Operator, Query, RecvCtx, SendCtx, SpillStats};
 
 type Ident = usize;
+type Row = Tuple;
+
+// The relations a stratum has indexed for joins, by the columns they're keyed
+// on.
+type Arrangements = HashMap<(Ident, Vec<usize>), Arranged<Row, (Row, Diff)>>;
 
 #[derive(Debug, Clone)]
 enum ColExpr {
@@ -20,23 +44,104 @@ enum ColExpr {
     Var(usize),
 }
 
+// An arithmetic expression over the columns of a row.
+#[derive(Debug, Clone)]
+enum Scalar {
+    Col(usize),
+    Datum(Datum),
+    Arith(ArithOp, Box<Scalar>, Box<Scalar>),
+}
+
+impl Scalar {
+    // None if the expression doesn't make sense for this row, like adding
+    // atoms or dividing by zero.
+    fn eval(&self, row: &[Datum]) -> Option<Datum> {
+        match self {
+            Scalar::Col(idx) => Some(row[*idx]),
+            Scalar::Datum(d) => Some(*d),
+            Scalar::Arith(op, l, r) => op.apply(&l.eval(row)?, &r.eval(row)?),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 struct Predicate {
     name: Ident,
     constants: Vec<(usize, Datum)>,
     // Index and name given to it.
     variables: Vec<(usize, Ident)>,
+    // Index, function and the variable it aggregates over.
+    aggregate: Option<(usize, Aggregate, Ident)>,
+    negated: bool,
+}
+
+impl Predicate {
+    fn arity(&self) -> usize {
+        self.constants.len() + self.variables.len() + self.aggregate.iter().count()
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Term {
+    Var(Ident),
+    Datum(Datum),
+    Arith(ArithOp, Box<Term>, Box<Term>),
+}
+
+impl Term {
+    fn vars(&self, out: &mut Vec<Ident>) {
+        match self {
+            Term::Var(v) => out.push(*v),
+            Term::Datum(_) => {}
+            Term::Arith(_, l, r) => {
+                l.vars(out);
+                r.vars(out);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Constraint {
+    lhs: Term,
+    op: CmpOp,
+    rhs: Term,
 }
 
 #[derive(Debug, Clone)]
+struct Clause {
+    head: Predicate,
+    // The relations the rule reads, positive and negated.
+    body: Vec<Predicate>,
+    constraints: Vec<Constraint>,
+}
+
+#[derive(Debug, Clone, Default)]
 struct Relation {
-    clauses: Vec<(Predicate, Vec<Predicate>)>,
+    clauses: Vec<Clause>,
+    // Facts loaded from files, which would be too many to keep as clauses.
+    facts: Vec<Row>,
+    // Files to load facts from once the program is run.
+    inputs: Vec<Input>,
+    // Files to write the relation to once the program has run.
+    outputs: Vec<Output>,
+    // The names and types of its columns, if they were declared.
+    decl: Option<Vec<(String, Type)>>,
+    // Otherwise, the types worked out for its columns from how it's used,
+    // where there's just the one.
+    types: Vec<Option<Type>>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Program {
     idents: HashMap<String, Ident>,
     relations: BTreeMap<Ident, Relation>,
+    // Whether to join cyclic rule bodies all at once, rather than a
+    // predicate at a time.
+    multiway_join: bool,
+    // About how many bytes each index a join reads from can take up before
+    // it's spilled to disk, if there's a limit.
+    memory_limit: Option<usize>,
 }
 
 impl Program {
@@ -44,23 +149,127 @@ impl Program {
         Program {
             idents: HashMap::new(),
             relations: BTreeMap::new(),
+            multiway_join: false,
+            memory_limit: None,
         }
     }
 
-    pub fn build(s: &str) -> Self {
-        let s = parse(s).unwrap();
+    // Makes rules whose bodies are cyclic, like the three edges of a
+    // triangle, be joined with a worst-case optimal multiway join. Joining
+    // them a predicate at a time can build up many more partial results than
+    // there are answers.
+    pub fn set_multiway_join(&mut self, on: bool) {
+        self.multiway_join = on;
+    }
+
+    // Limits how much memory each of the indexes that joins look records up
+    // in takes up, writing the rest to temporary files. It's per index rather
+    // than for the whole program: a join reads from two, and the state kept
+    // for negation, aggregates and multiway joins is never spilled. Joins
+    // that spill get slower, since looking up a key can mean reading from
+    // disk.
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.memory_limit = limit;
+    }
+
+    pub fn build(s: &str) -> anyhow::Result<Self> {
         let mut p = Program::new();
+        p.add(s)?;
+        Ok(p)
+    }
+
+    // Parses some more clauses into the program. Nothing is added if there
+    // are any errors.
+    pub fn add(&mut self, s: &str) -> anyhow::Result<()> {
+        let s = parse(s)?;
+        let mut declared = HashSet::new();
+        for directive in &s.directives {
+            if let Directive::Decl { rel, .. } = directive {
+                let before = self.idents.get(rel).and_then(|id| self.relations.get(id));
+                if !declared.insert(rel) || before.is_some_and(|rel| rel.decl.is_some()) {
+                    bail!("{} is declared more than once", rel);
+                }
+            }
+        }
+
+        let mut clauses = Vec::new();
         for clause in s.clauses {
-            p.clause(
-                (&clause.head.name, clause.head.args),
-                &clause
-                    .body
-                    .into_iter()
-                    .map(|pred| (pred.name, pred.args))
-                    .collect::<Vec<_>>(),
-            )
+            let head = self.intern_predicate(&clause.head.name, &clause.head.args)?;
+            let (body, constraints) = self.intern_body(&clause.body)?;
+            clauses.push(Clause {
+                head,
+                body,
+                constraints,
+            });
+        }
+        for clause in clauses {
+            self.add_clause(clause);
+        }
+        for directive in s.directives {
+            match directive {
+                Directive::Input {
+                    rel,
+                    path,
+                    delimiter,
+                } => {
+                    let name = self.intern(&rel);
+                    let input = Input::new(path.into(), delimiter);
+                    self.relations.entry(name).or_default().inputs.push(input);
+                }
+                Directive::Output { rel, path, format } => {
+                    let name = self.intern(&rel);
+                    let output = Output::new(path.into(), format);
+                    self.relations.entry(name).or_default().outputs.push(output);
+                }
+                Directive::Decl { rel, columns } => {
+                    let name = self.intern(&rel);
+                    self.relations.entry(name).or_default().decl = Some(columns);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn intern_body(
+        &mut self,
+        literals: &[Literal],
+    ) -> anyhow::Result<(Vec<Predicate>, Vec<Constraint>)> {
+        let mut body = Vec::new();
+        let mut constraints = Vec::new();
+        for lit in literals {
+            match lit {
+                Literal::Atom(pred) => body.push(self.intern_predicate(&pred.name, &pred.args)?),
+                Literal::Negated(pred) => body.push(Predicate {
+                    negated: true,
+                    ..self.intern_predicate(&pred.name, &pred.args)?
+                }),
+                Literal::Constraint(lhs, op, rhs) => constraints.push(Constraint {
+                    lhs: self.intern_term(lhs)?,
+                    op: *op,
+                    rhs: self.intern_term(rhs)?,
+                }),
+            }
         }
-        p
+        Ok((body, constraints))
+    }
+
+    // The names of every relation mentioned in the program, in order.
+    pub fn relations(&self) -> Vec<String> {
+        let mut names: Vec<_> = self
+            .relation_names()
+            .into_iter()
+            .map(|id| self.name(id).to_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn name(&self, id: Ident) -> &str {
+        self.idents
+            .iter()
+            .find(|(_, v)| **v == id)
+            .map(|(k, _)| k.as_str())
+            .unwrap()
     }
 
     fn intern(&mut self, name: &str) -> Ident {
@@ -73,141 +282,1093 @@ impl Program {
         }
     }
 
-    fn intern_predicate(&mut self, name: &str, args: &[Expr]) -> Predicate {
+    fn intern_predicate(&mut self, name: &str, args: &[Expr]) -> anyhow::Result<Predicate> {
+        let pred = name;
         let name = self.intern(name);
         let mut constants = Vec::new();
         let mut variables = Vec::new();
+        let mut aggregate = None;
         for (i, arg) in args.iter().enumerate() {
             match arg {
                 Expr::Datum(d) => {
-                    constants.push((i, d.clone()));
+                    constants.push((i, *d));
                 }
                 Expr::Var(s) => {
                     let s = self.intern(s);
                     variables.push((i, s));
                 }
+                Expr::Aggregate(agg, s) => {
+                    if aggregate.is_some() {
+                        bail!("{} can only have one aggregate", pred);
+                    }
+                    let s = self.intern(s);
+                    aggregate = Some((i, *agg, s));
+                }
+                Expr::Arith(..) => bail!("arithmetic is only allowed in constraints"),
             }
         }
-        Predicate {
+        Ok(Predicate {
             name,
             constants,
             variables,
-        }
+            aggregate,
+            negated: false,
+        })
     }
 
-    pub fn clause(&mut self, (name, args): (&str, Vec<Expr>), body: &[(String, Vec<Expr>)]) {
-        let head = self.intern_predicate(name, &args);
+    fn intern_term(&mut self, e: &Expr) -> anyhow::Result<Term> {
+        Ok(match e {
+            Expr::Var(s) => Term::Var(self.intern(s)),
+            Expr::Datum(d) => Term::Datum(*d),
+            Expr::Arith(op, l, r) => Term::Arith(
+                *op,
+                Box::new(self.intern_term(l)?),
+                Box::new(self.intern_term(r)?),
+            ),
+            Expr::Aggregate(..) => bail!("aggregates are only allowed in the heads of rules"),
+        })
+    }
 
-        let preds: Vec<_> = body
+    pub fn clause(
+        &mut self,
+        (name, args): (&str, Vec<Expr>),
+        body: &[(String, Vec<Expr>)],
+    ) -> anyhow::Result<()> {
+        let head = self.intern_predicate(name, &args)?;
+
+        let preds = body
             .iter()
             .map(|(pred, args)| self.intern_predicate(pred, args))
-            .collect();
+            .collect::<anyhow::Result<_>>()?;
 
-        match self.relations.get_mut(&head.name) {
-            Some(r) => r.clauses.push((head, preds)),
-            None => {
-                self.relations.insert(
-                    head.name,
-                    Relation {
-                        clauses: vec![(head, preds)],
-                    },
-                );
+        self.add_clause(Clause {
+            head,
+            body: preds,
+            constraints: Vec::new(),
+        });
+        Ok(())
+    }
+
+    fn add_clause(&mut self, clause: Clause) {
+        self.relations
+            .entry(clause.head.name)
+            .or_default()
+            .clauses
+            .push(clause);
+    }
+
+    // Moves the ground facts in the program out of its rules. Facts for an
+    // aggregated relation are instead turned into rules providing one more
+    // value to aggregate.
+    fn take_facts(&mut self) -> HashMap<Ident, HashSet<Row>> {
+        let mut facts: HashMap<_, HashSet<_>> = HashMap::new();
+        for (name, rel) in self.relations.iter_mut() {
+            if let Some((idx, agg, var)) = rel.clauses.iter().find_map(|c| c.head.aggregate) {
+                for row in rel.facts.drain(..) {
+                    rel.clauses.push(Clause {
+                        head: Predicate {
+                            name: *name,
+                            constants: row.iter().copied().enumerate().collect(),
+                            variables: Vec::new(),
+                            aggregate: None,
+                            negated: false,
+                        },
+                        body: Vec::new(),
+                        constraints: Vec::new(),
+                    });
+                }
+                for clause in &mut rel.clauses {
+                    let head = &mut clause.head;
+                    if !clause.body.is_empty() || head.aggregate.is_some() {
+                        continue;
+                    }
+                    if let Some(pos) = head.constants.iter().position(|(i, _)| *i == idx) {
+                        let (_, d) = head.constants.remove(pos);
+                        head.aggregate = Some((idx, agg, var));
+                        clause.constraints.push(Constraint {
+                            lhs: Term::Var(var),
+                            op: CmpOp::Eq,
+                            rhs: Term::Datum(d),
+                        });
+                    }
+                }
             }
+            if !rel.facts.is_empty() {
+                facts.entry(*name).or_default().extend(rel.facts.drain(..));
+            }
+            rel.clauses.retain(|clause| {
+                let head = &clause.head;
+                if clause.body.is_empty()
+                    && clause.constraints.is_empty()
+                    && head.variables.is_empty()
+                    && head.aggregate.is_none()
+                {
+                    let mut row: Vec<_> = head.constants.clone();
+                    row.sort_by_key(|(idx, _)| *idx);
+                    facts
+                        .entry(*name)
+                        .or_default()
+                        .insert(row.into_iter().map(|(_, d)| d).collect());
+                    false
+                } else {
+                    true
+                }
+            });
         }
+        facts
     }
 
-    pub fn render(mut self, out_rel: &str) -> Vec<Vec<Datum>> {
-        let out_rel = self.intern(out_rel);
-        let mut q = Query::new();
+    // The column and function a relation's rules aggregate by, if any.
+    fn aggregate(&self, rel: Ident) -> Option<(usize, Aggregate)> {
+        let rel = self.relations.get(&rel)?;
+        rel.clauses
+            .iter()
+            .find_map(|clause| clause.head.aggregate)
+            .map(|(idx, agg, _)| (idx, agg))
+    }
 
-        let mut ops = HashMap::new();
-        for (name, _) in self.relations.iter() {
-            let (input, operator): (_, Operator<Vec<Datum>>) = q.merge();
-            ops.insert(name, (input, operator.distinct()));
+    // Splits the relations defined by rules into strata: groups of mutually
+    // recursive relations, in an order where each only depends on the ones
+    // before it. A relation can't depend on the negation of a relation in its
+    // own stratum, since then there's no point at which the negated relation
+    // is complete. The same goes for counts and sums, but not for mins and
+    // maxes, which can be computed recursively.
+    fn strata(&self) -> anyhow::Result<Vec<Vec<Ident>>> {
+        let (components, problems) = self.stratify();
+        if let Some((message, _)) = problems.first() {
+            bail!("{}", message);
         }
+        Ok(components)
+    }
 
-        for (name, rel) in self.relations.iter() {
-            let (merged, _) = &*ops.get(name).unwrap();
+    // Splits the relations into strongly connected components, each after
+    // the ones it depends on, along with the rules that stop them from being
+    // strata: ones that negate or aggregate over their own component.
+    fn stratify(&self) -> (Vec<Vec<Ident>>, Vec<(String, &Clause)>) {
+        // Tarjan's algorithm, which finds each strongly connected component
+        // after all the ones it depends on.
+        struct Tarjan<'a> {
+            program: &'a Program,
+            index: HashMap<Ident, usize>,
+            low: HashMap<Ident, usize>,
+            stack: Vec<Ident>,
+            on_stack: HashSet<Ident>,
+            components: Vec<Vec<Ident>>,
+        }
 
-            for (head, body) in &rel.clauses {
-                let mut processed_vars = HashMap::new();
-                let mut len = 0;
-                let mut join = q.source(|send: &SendCtx<Vec<Datum>>| send.push(vec![]));
-                for pred in body {
-                    let (_, operator) = &*ops.get(&pred.name).unwrap();
+        impl<'a> Tarjan<'a> {
+            fn visit(&mut self, v: Ident) {
+                let i = self.index.len();
+                self.index.insert(v, i);
+                self.low.insert(v, i);
+                self.stack.push(v);
+                self.on_stack.insert(v);
 
-                    let p = pred.clone();
-                    let filtered = operator.clone().filter(move |row| {
-                        p.constants.iter().all(|(col, datum)| row[*col] == *datum)
-                    });
+                let deps: Vec<_> = self.program.relations[&v]
+                    .clauses
+                    .iter()
+                    .flat_map(|clause| clause.body.iter().map(|pred| pred.name))
+                    .filter(|name| self.program.relations.contains_key(name))
+                    .collect();
+                for w in deps {
+                    if !self.index.contains_key(&w) {
+                        self.visit(w);
+                        let low = self.low[&v].min(self.low[&w]);
+                        self.low.insert(v, low);
+                    } else if self.on_stack.contains(&w) {
+                        let low = self.low[&v].min(self.index[&w]);
+                        self.low.insert(v, low);
+                    }
+                }
+
+                if self.low[&v] == self.index[&v] {
+                    let mut component = Vec::new();
+                    loop {
+                        let w = self.stack.pop().unwrap();
+                        self.on_stack.remove(&w);
+                        component.push(w);
+                        if w == v {
+                            break;
+                        }
+                    }
+                    component.sort_unstable();
+                    self.components.push(component);
+                }
+            }
+        }
 
-                    let mut left_key = Vec::new();
-                    let mut right_key = Vec::new();
+        let mut t = Tarjan {
+            program: self,
+            index: HashMap::new(),
+            low: HashMap::new(),
+            stack: Vec::new(),
+            on_stack: HashSet::new(),
+            components: Vec::new(),
+        };
+        for name in self.relations.keys() {
+            if !t.index.contains_key(name) {
+                t.visit(*name);
+            }
+        }
 
-                    for (idx, name) in &pred.variables {
-                        if let Some(join_idx) = processed_vars.get(&name) {
-                            left_key.push(*join_idx);
-                            right_key.push(*idx);
-                        } else {
-                            processed_vars.insert(name, len + idx);
+        let mut stratum = HashMap::new();
+        for (i, component) in t.components.iter().enumerate() {
+            for name in component {
+                stratum.insert(*name, i);
+            }
+        }
+        let mut problems = Vec::new();
+        for (name, rel) in &self.relations {
+            for clause in &rel.clauses {
+                for pred in &clause.body {
+                    if stratum.get(&pred.name) != Some(&stratum[name]) {
+                        continue;
+                    }
+                    if pred.negated {
+                        problems.push((
+                            format!(
+                                "can't stratify program: {} depends on the negation of {}, which depends on {}",
+                                self.name(*name),
+                                self.name(pred.name),
+                                self.name(*name),
+                            ),
+                            clause,
+                        ));
+                    }
+                    if let Some((_, agg, _)) = clause.head.aggregate {
+                        if !agg.monotone() {
+                            problems.push((
+                                format!(
+                                    "can't stratify program: {} aggregates over {}, which depends on {}",
+                                    self.name(*name),
+                                    self.name(pred.name),
+                                    self.name(*name),
+                                ),
+                                clause,
+                            ));
                         }
                     }
-                    len += pred.constants.len() + pred.variables.len();
-
-                    // Give each input the key structure... I guess if we were clever we'd remove them from the rhs.
-                    let keyed = filtered.map(move |row| {
-                        (
-                            right_key
-                                .iter()
-                                .map(|i| row[*i].clone())
-                                .collect::<Vec<_>>(),
-                            row,
-                        )
-                    });
-                    let keyed_join = join.clone().map(move |row| {
-                        (
-                            left_key.iter().map(|i| row[*i].clone()).collect::<Vec<_>>(),
-                            row,
-                        )
-                    });
-                    join = keyed_join.join(keyed).map(|(_k, v1, v2)| {
-                        v1.into_iter().chain(v2.into_iter()).collect::<Vec<_>>()
-                    });
                 }
+            }
+        }
+
+        (t.components, problems)
+    }
 
-                let arity = head.constants.len() + head.variables.len();
-                let mut projection: Vec<_> = (0..arity).map(|_| None).collect();
-                for (idx, v) in &head.variables {
-                    projection[*idx] = Some(ColExpr::Var(*processed_vars.get(v).unwrap()));
+    // Every relation mentioned in the program.
+    fn relation_names(&self) -> BTreeSet<Ident> {
+        let mut names: BTreeSet<Ident> = self.relations.keys().cloned().collect();
+        for rel in self.relations.values() {
+            for clause in &rel.clauses {
+                names.extend(clause.body.iter().map(|pred| pred.name));
+            }
+        }
+        names
+    }
+
+    // Builds the dataflow computing the tuples derived by a single rule, given
+    // the operators producing the changes to each relation, and to the ones
+    // it negates. Each derivation comes with a diff, so that a tuple going
+    // away from a relation retracts the derivations it was part of.
+    fn compile_clause(
+        &self,
+        q: &mut Query,
+        ops: &HashMap<Ident, Operator<(Row, Diff)>>,
+        negated_ops: &HashMap<Ident, Operator<(Row, Diff)>>,
+        arrangements: &mut Arrangements,
+        clause: &Clause,
+        sizes: &HashMap<Ident, usize>,
+    ) -> anyhow::Result<Operator<(Row, Diff)>> {
+        let Clause {
+            body, constraints, ..
+        } = clause;
+        let mut processed_vars = HashMap::new();
+        let mut len = 0;
+        let mut pending: Vec<_> = constraints.iter().collect();
+        let steps = self.plan(clause, sizes);
+        if self.multiway_join && cyclic(clause) {
+            let order = order(clause, &steps);
+            for (i, v) in order.iter().enumerate() {
+                processed_vars.insert(*v, i);
+            }
+            len = order.len();
+            let join = self.multiway(q, ops, clause, &steps, &order);
+            let join = Self::apply_constraints(join, &mut pending, &mut processed_vars, &mut len);
+            return self.finish(join, negated_ops, clause, &pending, &processed_vars);
+        }
+
+        let mut join = q.source(|send: &SendCtx<(Row, Diff)>| send.push((Tuple::new(), 1)));
+        join = Self::apply_constraints(join, &mut pending, &mut processed_vars, &mut len);
+        for (i, step) in steps.into_iter().enumerate() {
+            let pred = &body[step.idx];
+            let operator = ops.get(&pred.name).unwrap();
+
+            let mut left_key = Vec::new();
+            let mut right_key = Vec::new();
+            // Columns which have to be equal, because they're the same new
+            // variable.
+            let mut same = Vec::new();
+            let mut new_vars: HashMap<&Ident, usize> = HashMap::new();
+            for (idx, name) in &pred.variables {
+                if let Some(join_idx) = processed_vars.get(&name) {
+                    left_key.push(*join_idx);
+                    right_key.push(*idx);
+                } else if let Some(first) = new_vars.get(&name) {
+                    same.push((*first, *idx));
+                } else {
+                    new_vars.insert(name, *idx);
                 }
-                for (idx, v) in &head.constants {
-                    projection[*idx] = Some(ColExpr::Datum(v.clone()))
+            }
+
+            if i == 0 && pending.len() == constraints.len() {
+                // The join so far is still just the empty row, so there's
+                // nothing to join with.
+                let constants = pred.constants.clone();
+                join = operator.clone().filter(move |(row, _)| {
+                    constants.iter().all(|(col, datum)| row[*col] == *datum)
+                        && same.iter().all(|(a, b)| row[*a] == row[*b])
+                });
+            } else {
+                // The relation is keyed by the columns it's joined on, followed
+                // by the ones that have to be constants, so that every rule
+                // reading it with the same columns known shares one index.
+                let mut columns = right_key;
+                columns.extend(pred.constants.iter().map(|(col, _)| *col));
+                let arranged = arrangements
+                    .entry((pred.name, columns.clone()))
+                    .or_insert_with(|| {
+                        operator
+                            .clone()
+                            .map(move |(row, d)| {
+                                (columns.iter().map(|i| row[*i]).collect::<Row>(), (row, d))
+                            })
+                            .arrange_by_key()
+                    })
+                    .clone();
+                let constants: Vec<_> = pred.constants.iter().map(|(_, datum)| *datum).collect();
+                let keyed_join = join.clone().map(move |(row, d)| {
+                    let key = left_key
+                        .iter()
+                        .map(|i| row[*i])
+                        .chain(constants.iter().copied());
+                    (key.collect::<Row>(), (row, d))
+                });
+                join = keyed_join
+                    .arrange_by_key()
+                    .join(&arranged)
+                    .map(|(_k, (v1, d1), (v2, d2))| (v1.concat(&v2), d1 * d2));
+                if !same.is_empty() {
+                    let offset = len;
+                    join = join.filter(move |(row, _)| {
+                        same.iter()
+                            .all(|(a, b)| row[offset + *a] == row[offset + *b])
+                    });
                 }
+            }
+            for (name, idx) in new_vars {
+                processed_vars.insert(name, len + idx);
+            }
+            len += pred.arity();
+            join = Self::apply_constraints(join, &mut pending, &mut processed_vars, &mut len);
+        }
+        self.finish(join, negated_ops, clause, &pending, &processed_vars)
+    }
 
-                let proj: Vec<_> = projection.drain(..).map(|x| x.unwrap()).collect();
+    // Joins every positive predicate of a rule's body at once, binding the
+    // variables in the given order. Each row has the value of every variable,
+    // in that order.
+    fn multiway(
+        &self,
+        q: &mut Query,
+        ops: &HashMap<Ident, Operator<(Row, Diff)>>,
+        clause: &Clause,
+        steps: &[Step],
+        order: &[&Ident],
+    ) -> Operator<(Row, Diff)> {
+        let mut inputs = Vec::new();
+        for step in steps {
+            let pred = &clause.body[step.idx];
+            // The first column each variable is in, and the variable each of
+            // those is, as its position in the order.
+            let mut columns = Vec::new();
+            let mut binds = Vec::new();
+            let mut same = Vec::new();
+            for (idx, name) in &pred.variables {
+                let var = order.iter().position(|v| *v == name).unwrap();
+                match binds.iter().position(|b| *b == var) {
+                    Some(i) => same.push((columns[i], *idx)),
+                    None => {
+                        columns.push(*idx);
+                        binds.push(var);
+                    }
+                }
+            }
 
-                join = join.map(move |row| {
-                    proj.iter()
-                        .map(|e| match e {
-                            ColExpr::Datum(d) => d.clone(),
-                            ColExpr::Var(idx) => row[*idx].clone(),
-                        })
-                        .collect::<Vec<_>>()
+            let constants = pred.constants.clone();
+            let op = ops
+                .get(&pred.name)
+                .unwrap()
+                .clone()
+                .filter(move |(row, _)| {
+                    constants.iter().all(|(col, datum)| row[*col] == *datum)
+                        && same.iter().all(|(a, b)| row[*a] == row[*b])
+                })
+                .map(move |(row, d)| (columns.iter().map(|col| row[*col]).collect::<Row>(), d));
+            inputs.push((op, binds));
+        }
+        q.multiway_join(inputs)
+            .map(|(row, d): (Vec<Datum>, Diff)| (Row::from(&row[..]), d))
+    }
+
+    // Finishes off a rule once its body has been joined: checks every
+    // constraint got applied, removes the rows matching negated relations,
+    // and projects out the head.
+    fn finish(
+        &self,
+        mut join: Operator<(Row, Diff)>,
+        negated_ops: &HashMap<Ident, Operator<(Row, Diff)>>,
+        clause: &Clause,
+        pending: &[&Constraint],
+        processed_vars: &HashMap<&Ident, usize>,
+    ) -> anyhow::Result<Operator<(Row, Diff)>> {
+        let Clause { head, body, .. } = clause;
+        if let Some(c) = pending.first() {
+            let mut vars = Vec::new();
+            c.lhs.vars(&mut vars);
+            c.rhs.vars(&mut vars);
+            let v = vars
+                .into_iter()
+                .find(|v| !processed_vars.contains_key(v))
+                .unwrap();
+            bail!(
+                "variable {} in a constraint on {} isn't bound by the rule's body",
+                self.name(v),
+                self.name(head.name)
+            );
+        }
+
+        // Once all the variables are bound, throw away the rows which match a
+        // tuple in a negated relation.
+        for pred in body.iter().filter(|pred| pred.negated) {
+            let key = self.project(pred, processed_vars)?;
+            let negated = negated_ops.get(&pred.name).unwrap().clone();
+            join = join
+                .map(move |(row, d)| ((eval(&key, &row), row), d))
+                .as_collection()
+                .antijoin(negated.as_collection())
+                .inner()
+                .map(|((_, row), d)| (row, d));
+        }
+
+        let proj = self.project(head, processed_vars)?;
+        Ok(join.map(move |(row, d)| (eval(&proj, &row), d)))
+    }
+
+    // Applies every constraint that can be, now that the variables in `vars`
+    // are bound. Comparisons filter the rows, and an equality with an unbound
+    // variable on one side binds it to the other, which can in turn make more
+    // constraints applicable.
+    fn apply_constraints<'a>(
+        mut join: Operator<(Row, Diff)>,
+        pending: &mut Vec<&'a Constraint>,
+        vars: &mut HashMap<&'a Ident, usize>,
+        len: &mut usize,
+    ) -> Operator<(Row, Diff)> {
+        loop {
+            let ready = pending.iter().position(|c| {
+                scalar(&c.lhs, vars).is_some() && scalar(&c.rhs, vars).is_some()
+                    || c.op == CmpOp::Eq && binding(c, vars).is_some()
+            });
+            let c = match ready {
+                Some(i) => pending.remove(i),
+                None => return join,
+            };
+
+            if let (Some(lhs), Some(rhs)) = (scalar(&c.lhs, vars), scalar(&c.rhs, vars)) {
+                let op = c.op;
+                join = join.filter(move |(row, _)| match (lhs.eval(row), rhs.eval(row)) {
+                    (Some(l), Some(r)) => op.apply(&l, &r),
+                    _ => false,
+                });
+            } else {
+                let (var, value) = binding(c, vars).unwrap();
+                vars.insert(var, *len);
+                *len += 1;
+                join = join.unary(move |recv: &RecvCtx<(Row, Diff)>, send| {
+                    send.give_iterator(recv.take_all().into_iter().filter_map(|(mut row, d)| {
+                        row.push(value.eval(&row)?);
+                        Some((row, d))
+                    }))
                 });
-                q.wire(join, (*merged).clone())
             }
         }
+    }
 
-        let (_, out) = ops.get(&out_rel).unwrap().clone();
+    // How to compute the arguments of a predicate from a row with the given
+    // variables bound.
+    fn project(
+        &self,
+        pred: &Predicate,
+        vars: &HashMap<&Ident, usize>,
+    ) -> anyhow::Result<Vec<ColExpr>> {
+        let mut projection: Vec<_> = (0..pred.arity()).map(|_| None).collect();
+        let aggregated = pred.aggregate.iter().map(|(idx, _, v)| (*idx, *v));
+        for (idx, v) in pred.variables.iter().cloned().chain(aggregated) {
+            match vars.get(&v) {
+                Some(col) => projection[idx] = Some(ColExpr::Var(*col)),
+                None => bail!(
+                    "variable {} in {} isn't bound by the rule's body",
+                    self.name(v),
+                    self.name(pred.name)
+                ),
+            }
+        }
+        for (idx, v) in &pred.constants {
+            projection[*idx] = Some(ColExpr::Datum(*v))
+        }
+        Ok(projection.drain(..).map(|x| x.unwrap()).collect())
+    }
 
-        let out_rows = Rc::new(RefCell::new(Vec::new()));
-        let moved = out_rows.clone();
-        out.sink(move |r| (*moved).borrow_mut().push(r));
+    // Compiles the program into a dataflow that stays around, so facts can be
+    // added to and removed from it later.
+    pub fn instantiate(mut self, out_rel: &str) -> anyhow::Result<Instance> {
+        if let Some(d) = self
+            .check()
+            .into_iter()
+            .find(|d| d.severity == Severity::Error)
+        {
+            bail!("{}", d);
+        }
+        let out_rel = match self.idents.get(out_rel) {
+            Some(id) if self.relation_names().contains(id) => *id,
+            _ => bail!("unknown relation {}", out_rel),
+        };
+        self.load_inputs()?;
+        self.coerce()?;
+        let facts = self.take_facts();
+        let mut instance = Instance {
+            program: self,
+            out_rel,
+            facts,
+            sizes: HashMap::new(),
+            strata: Vec::new(),
+            relations: HashMap::new(),
+            counts: HashMap::new(),
+            derivations: 0,
+        };
+        instance.build()?;
+        Ok(instance)
+    }
 
-        (*q.df).borrow_mut().run();
+    pub fn render(self, out_rel: &str) -> anyhow::Result<Vec<Vec<Datum>>> {
+        Ok(self.instantiate(out_rel)?.contents())
+    }
 
-        let x = (*out_rows).borrow_mut().drain(..).collect();
-        x
+    // Like render, but for several relations at once, from a single run of
+    // the program.
+    pub fn render_all(
+        self,
+        out_rels: &[&str],
+    ) -> anyhow::Result<BTreeMap<String, Vec<Vec<Datum>>>> {
+        let instance = match out_rels.first() {
+            Some(rel) => self.instantiate(rel)?,
+            None => return Ok(BTreeMap::new()),
+        };
+        out_rels
+            .iter()
+            .map(|rel| Ok((rel.to_string(), instance.contents_of(rel)?)))
+            .collect()
+    }
+}
+
+// Compiles a term against the columns the variables are bound to, or returns
+// None if some of them aren't bound yet.
+fn scalar(t: &Term, vars: &HashMap<&Ident, usize>) -> Option<Scalar> {
+    match t {
+        Term::Var(v) => vars.get(v).map(|col| Scalar::Col(*col)),
+        Term::Datum(d) => Some(Scalar::Datum(*d)),
+        Term::Arith(op, l, r) => Some(Scalar::Arith(
+            *op,
+            Box::new(scalar(l, vars)?),
+            Box::new(scalar(r, vars)?),
+        )),
+    }
+}
+
+// If the constraint is an equality between an unbound variable and something
+// that can be computed, the variable and how to compute it.
+fn binding<'a>(c: &'a Constraint, vars: &HashMap<&Ident, usize>) -> Option<(&'a Ident, Scalar)> {
+    match (&c.lhs, &c.rhs) {
+        (Term::Var(v), t) | (t, Term::Var(v)) if !vars.contains_key(v) => {
+            Some((v, scalar(t, vars)?))
+        }
+        _ => None,
+    }
+}
+
+fn eval(proj: &[ColExpr], row: &[Datum]) -> Row {
+    proj.iter()
+        .map(|e| match e {
+            ColExpr::Datum(d) => *d,
+            ColExpr::Var(idx) => row[*idx],
+        })
+        .collect()
+}
+
+// The changes to an Instance's output relation caused by a change to its
+// facts.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Delta {
+    pub inserted: Vec<Vec<Datum>>,
+    pub retracted: Vec<Vec<Datum>>,
+}
+
+// The dataflow for the rules of a group of mutually recursive relations.
+// Every relation the rules read is fed in from the outside as changes to its
+// contents, and the changes to how many ways each tuple can be derived are
+// collected, so that the Instance can feed back in just the tuples that were
+// added or removed: each round joins only the last round's changes against
+// everything seen before, which is semi-naive evaluation.
+//
+// An aggregated relation's rules all feed a single reduce, which retracts a
+// group's old row and sends its new one whenever its aggregate changes. Facts
+// for the relation are fed into the reduce too, as more values to aggregate.
+struct Stratum {
+    relations: Vec<Ident>,
+    query: Query,
+    inputs: HashMap<Ident, InputHandle<(Row, Diff)>>,
+    negated_inputs: HashMap<Ident, InputHandle<(Row, Diff)>>,
+    fact_inputs: HashMap<Ident, InputHandle<(Row, Diff)>>,
+    derived: Rc<RefCell<Vec<(Ident, Row, Diff)>>>,
+    // Whether the rules read the relations they define.
+    recursive: bool,
+}
+
+impl Stratum {
+    fn new(
+        program: &Program,
+        relations: &[Ident],
+        sizes: &HashMap<Ident, usize>,
+    ) -> anyhow::Result<Self> {
+        let mut query = Query::new();
+        if let Some(limit) = program.memory_limit {
+            query.spill(limit, std::env::temp_dir());
+        }
+        let mut inputs = HashMap::new();
+        let mut negated_inputs = HashMap::new();
+        let mut fact_inputs = HashMap::new();
+        let mut ops = HashMap::new();
+        let mut negated_ops = HashMap::new();
+        let mut arrangements = HashMap::new();
+        let derived = Rc::new(RefCell::new(Vec::new()));
+
+        for name in relations {
+            let mut outputs = Vec::new();
+            for clause in &program.relations[name].clauses {
+                for pred in &clause.body {
+                    let (inputs, ops) = if pred.negated {
+                        (&mut negated_inputs, &mut negated_ops)
+                    } else {
+                        (&mut inputs, &mut ops)
+                    };
+                    if let Entry::Vacant(e) = ops.entry(pred.name) {
+                        let (handle, op) = query.input();
+                        inputs.insert(pred.name, handle);
+                        e.insert(op);
+                    }
+                }
+                outputs.push(program.compile_clause(
+                    &mut query,
+                    &ops,
+                    &negated_ops,
+                    &mut arrangements,
+                    clause,
+                    sizes,
+                )?);
+            }
+
+            if let Some((idx, agg)) = program.aggregate(*name) {
+                let (handle, facts) = query.input();
+                fact_inputs.insert(*name, handle);
+                outputs.push(facts);
+                // Each binding of a rule's body is a separate derivation, so
+                // a value counts once for each way it was derived.
+                let output = query
+                    .concat(outputs)
+                    .map(move |(mut row, d)| {
+                        let value = row.remove(idx);
+                        ((row, value), d)
+                    })
+                    .as_collection()
+                    .reduce(move |_, values: &[(Datum, Diff)]| {
+                        agg.apply(values).into_iter().map(|acc| (acc, 1)).collect()
+                    })
+                    .map(move |(mut row, acc)| {
+                        row.insert(idx, acc);
+                        row
+                    })
+                    .inner();
+                outputs = vec![output];
+            }
+
+            let moved = derived.clone();
+            let name = *name;
+            query
+                .concat(outputs)
+                .sink(move |(row, d)| (*moved).borrow_mut().push((name, row, d)));
+        }
+
+        let recursive = relations.iter().any(|name| inputs.contains_key(name));
+        Ok(Stratum {
+            relations: relations.to_vec(),
+            query,
+            inputs,
+            negated_inputs,
+            fact_inputs,
+            derived,
+            recursive,
+        })
+    }
+
+    // Whether a change to a relation matters to the rules.
+    fn reads(&self, name: &Ident) -> bool {
+        self.inputs.contains_key(name) || self.negated_inputs.contains_key(name)
+    }
+
+    // Whether the stratum aggregates over itself with a min or max, which
+    // can't follow a tuple being removed: every other value in the group
+    // could be derived from the group's own row, so taking out the smallest
+    // one would just keep finding slightly bigger ones.
+    fn aggregates_recursively(&self, program: &Program) -> bool {
+        self.recursive
+            && self
+                .relations
+                .iter()
+                .any(|name| program.aggregate(*name).is_some())
+    }
+
+    fn run(
+        &mut self,
+        changes: HashMap<Ident, Vec<(Row, Diff)>>,
+        facts: HashMap<Ident, Vec<(Row, Diff)>>,
+    ) -> anyhow::Result<Vec<(Ident, Row, Diff)>> {
+        for (name, mut rows) in changes {
+            let negated = self.negated_inputs.get(&name);
+            if let Some(input) = self.inputs.get(&name) {
+                // Only a relation that's read both ways needs a copy.
+                if let Some(negated) = negated {
+                    negated.give_iterator(rows.iter().cloned());
+                }
+                input.give_vec(&mut rows);
+            } else if let Some(negated) = negated {
+                negated.give_vec(&mut rows);
+            }
+        }
+        for (name, mut rows) in facts {
+            if let Some(input) = self.fact_inputs.get(&name) {
+                input.give_vec(&mut rows);
+            }
+        }
+        (*self.query.df).borrow_mut().run();
+        if let Some(e) = self.query.spill_error() {
+            bail!("can't spill to disk: {}", e);
+        }
+        Ok((*self.derived).borrow_mut().drain(..).collect())
+    }
+}
+
+// A compiled Program which facts can be inserted into and retracted from.
+// Either only computes what changes as a result: the Instance keeps count of
+// how many ways each tuple can be derived, and a tuple is only removed once
+// that goes to zero.
+//
+// That isn't enough in recursive rules, where tuples can be derived from each
+// other in a cycle, so there a tuple that loses any of its derivations is
+// removed straight away. Once everything derived from it has been removed as
+// well, the ones which can still be derived some other way are put back.
+pub struct Instance {
+    program: Program,
+    out_rel: Ident,
+    facts: HashMap<Ident, HashSet<Row>>,
+    sizes: HashMap<Ident, usize>,
+    strata: Vec<Stratum>,
+    relations: HashMap<Ident, HashSet<Row>>,
+    // How many ways each tuple of a relation with rules can be derived,
+    // where being a fact is one of them.
+    counts: HashMap<(Ident, Row), Diff>,
+    derivations: usize,
+}
+
+impl Instance {
+    fn build(&mut self) -> anyhow::Result<()> {
+        self.sizes = self.program.estimates(&self.facts);
+        self.strata = self
+            .program
+            .strata()?
+            .iter()
+            .map(|relations| Stratum::new(&self.program, relations, &self.sizes))
+            .collect::<anyhow::Result<_>>()?;
+        self.relations = self
+            .program
+            .relation_names()
+            .into_iter()
+            .map(|name| (name, HashSet::new()))
+            .collect();
+
+        let facts = self
+            .facts
+            .iter()
+            .map(|(name, rows)| (*name, rows.iter().map(|row| (row.clone(), 1)).collect()))
+            .collect();
+        self.propagate(facts)?;
+        Ok(())
+    }
+
+    // Brings each stratum up to date with some changes to the facts, returning
+    // the tuples that were added to or removed from every relation as a
+    // result.
+    fn propagate(
+        &mut self,
+        mut facts: HashMap<Ident, Vec<(Row, Diff)>>,
+    ) -> anyhow::Result<HashMap<Ident, Vec<(Row, Diff)>>> {
+        let mut changes: HashMap<Ident, Vec<(Row, Diff)>> = HashMap::new();
+        // Relations which are only ever read don't have a stratum.
+        for (name, rows) in &facts {
+            if self.program.relations.contains_key(name) {
+                continue;
+            }
+            let rel = self.relations.get_mut(name).unwrap();
+            for (row, d) in rows {
+                if *d > 0 && rel.insert(row.clone()) || *d < 0 && rel.remove(row) {
+                    changes.entry(*name).or_default().push((row.clone(), *d));
+                }
+            }
+        }
+        for i in 0..self.strata.len() {
+            let facts = self.strata[i]
+                .relations
+                .iter()
+                .filter_map(|name| Some((*name, facts.remove(name)?)))
+                .collect();
+            self.update(i, facts, &mut changes)?;
+        }
+        Ok(changes)
+    }
+
+    // Runs a stratum to a fixpoint given changes to its facts and to the
+    // relations it reads, adding the changes to its own relations to
+    // `changes`.
+    fn update(
+        &mut self,
+        i: usize,
+        mut facts: HashMap<Ident, Vec<(Row, Diff)>>,
+        changes: &mut HashMap<Ident, Vec<(Row, Diff)>>,
+    ) -> anyhow::Result<()> {
+        let stratum = &self.strata[i];
+        let mut round: HashMap<Ident, Vec<(Row, Diff)>> = changes
+            .iter()
+            .filter(|(name, _)| stratum.reads(name))
+            .map(|(name, rows)| (*name, rows.clone()))
+            .collect();
+        // The net change to each of the stratum's tuples.
+        let mut net: HashMap<(Ident, Row), Diff> = HashMap::new();
+
+        let retracts = facts
+            .values()
+            .chain(round.values())
+            .flatten()
+            .any(|(_, d)| *d < 0)
+            || round
+                .keys()
+                .any(|name| stratum.negated_inputs.contains_key(name));
+        if retracts && stratum.aggregates_recursively(&self.program) {
+            // Start the stratum over from what it reads as it is now.
+            let relations = stratum.relations.clone();
+            self.strata[i] = Stratum::new(&self.program, &relations, &self.sizes)?;
+            self.counts.retain(|(name, _), _| !relations.contains(name));
+            for name in &relations {
+                for row in self.relations.get_mut(name).unwrap().drain() {
+                    net.insert((*name, row), -1);
+                }
+                let rows = self.facts.get(name).into_iter().flatten();
+                facts.insert(*name, rows.map(|row| (row.clone(), 1)).collect());
+            }
+            let stratum = &self.strata[i];
+            round = self
+                .relations
+                .iter()
+                .filter(|(name, _)| stratum.reads(name))
+                .map(|(name, rows)| (*name, rows.iter().map(|row| (row.clone(), 1)).collect()))
+                .collect();
+        }
+
+        // Facts for aggregated relations are fed into the stratum, and the
+        // rest just count as one more derivation.
+        let (aggregated, facts): (HashMap<_, _>, HashMap<_, _>) = facts
+            .into_iter()
+            .partition(|(name, _)| self.program.aggregate(*name).is_some());
+        let mut derived: Vec<_> = facts
+            .into_iter()
+            .flat_map(|(name, rows)| rows.into_iter().map(move |(row, d)| (name, row, d)))
+            .collect();
+        let mut aggregated = Some(aggregated);
+        // Tuples which lost a derivation in a recursive rule, and were taken
+        // out until it's known whether they can still be derived.
+        let mut removed = HashSet::new();
+        loop {
+            let stratum = &mut self.strata[i];
+            let out = stratum.run(mem::take(&mut round), aggregated.take().unwrap_or_default())?;
+            self.derivations += out.len();
+            derived.extend(out);
+
+            let recursive = stratum.recursive;
+            let mut totals: HashMap<(Ident, Row), Diff> = HashMap::new();
+            for (name, row, d) in derived.drain(..) {
+                *totals.entry((name, row)).or_default() += d;
+            }
+            for ((name, row), d) in totals {
+                let count = self.counts.entry((name, row.clone())).or_default();
+                *count += d;
+                let count = *count;
+                if count == 0 {
+                    self.counts.remove(&(name, row.clone()));
+                }
+                // An aggregate's reduce already sends exactly when its rows
+                // change.
+                let exact = !recursive || self.program.aggregate(name).is_some();
+                let rel = self.relations.get_mut(&name).unwrap();
+                let diff = if rel.contains(&row) {
+                    if count <= 0 || d < 0 && !exact {
+                        if count > 0 {
+                            removed.insert((name, row.clone()));
+                        }
+                        rel.remove(&row);
+                        -1
+                    } else {
+                        continue;
+                    }
+                } else if count > 0 && !removed.contains(&(name, row.clone())) {
+                    rel.insert(row.clone());
+                    1
+                } else {
+                    continue;
+                };
+                *net.entry((name, row.clone())).or_default() += diff;
+                round.entry(name).or_default().push((row, diff));
+            }
+
+            if round.is_empty() {
+                // Everything derived from the removed tuples has been removed
+                // too, so the ones which still have derivations are put back.
+                for (name, row) in removed.drain() {
+                    if self
+                        .counts
+                        .get(&(name, row.clone()))
+                        .is_some_and(|c| *c > 0)
+                    {
+                        self.relations.get_mut(&name).unwrap().insert(row.clone());
+                        *net.entry((name, row.clone())).or_default() += 1;
+                        round.entry(name).or_default().push((row, 1));
+                    }
+                }
+                if round.is_empty() {
+                    break;
+                }
+            }
+        }
+
+        for ((name, row), d) in net {
+            if d != 0 {
+                changes.entry(name).or_default().push((row, d));
+            }
+        }
+        Ok(())
+    }
+
+    fn relation(&self, rel: &str) -> anyhow::Result<Ident> {
+        match self.program.idents.get(rel) {
+            Some(id) if self.relations.contains_key(id) => Ok(*id),
+            _ => bail!("unknown relation {}", rel),
+        }
+    }
+
+    pub fn insert_fact(&mut self, rel: &str, mut fact: Vec<Datum>) -> anyhow::Result<Delta> {
+        let rel = self.relation(rel)?;
+        self.program.coerce_row(rel, &mut fact)?;
+        let fact = Row::from(&fact[..]);
+        if !self.facts.entry(rel).or_default().insert(fact.clone()) {
+            return Ok(Delta::default());
+        }
+        self.change_fact(rel, fact, 1)
+    }
+
+    pub fn retract_fact(&mut self, rel: &str, fact: &[Datum]) -> anyhow::Result<Delta> {
+        let rel = self.relation(rel)?;
+        let mut fact = Row::from(fact);
+        self.program.coerce_row(rel, &mut fact)?;
+        if !self.facts.entry(rel).or_default().remove(&fact) {
+            return Ok(Delta::default());
+        }
+        self.change_fact(rel, fact, -1)
+    }
+
+    fn change_fact(&mut self, rel: Ident, fact: Row, diff: Diff) -> anyhow::Result<Delta> {
+        let mut facts = HashMap::new();
+        facts.insert(rel, vec![(fact, diff)]);
+        let mut delta = Delta::default();
+        for (row, d) in self
+            .propagate(facts)?
+            .remove(&self.out_rel)
+            .into_iter()
+            .flatten()
+        {
+            if d > 0 {
+                delta.inserted.push(row.to_vec());
+            } else {
+                delta.retracted.push(row.to_vec());
+            }
+        }
+        Ok(delta)
+    }
+
+    // The current contents of the output relation.
+    pub fn contents(&self) -> Vec<Vec<Datum>> {
+        self.relations
+            .get(&self.out_rel)
+            .into_iter()
+            .flatten()
+            .map(|row| row.to_vec())
+            .collect()
+    }
+
+    // The current contents of any relation in the program.
+    pub fn contents_of(&self, rel: &str) -> anyhow::Result<Vec<Vec<Datum>>> {
+        let rel = self.relation(rel)?;
+        Ok(self.relations[&rel]
+            .iter()
+            .map(|row| row.to_vec())
+            .collect())
+    }
+
+    // The types of a relation's columns, where they're known.
+    pub fn column_types(&self, rel: &str) -> anyhow::Result<Vec<Option<Type>>> {
+        let rel = self.relation(rel)?;
+        Ok(self.program.column_types(rel))
+    }
+
+    // How much the joins' indexes have spilled to disk.
+    pub fn spill_stats(&self) -> SpillStats {
+        let mut stats = SpillStats::default();
+        for stratum in &self.strata {
+            stats += stratum.query.spill_stats();
+        }
+        stats
+    }
+
+    // The number of tuples the rules have derived, including ones that were
+    // already known.
+    pub fn derivations(&self) -> usize {
+        self.derivations
     }
 }
 
@@ -217,11 +1378,64 @@ fn test_datalog() {
 
     walk("src/testdata/datalog", |f| {
         f.run(|test_case| {
-            let p = Program::build(&test_case.input);
+            // Queries come after the program, on a line starting with ?-.
+            let (input, query) = match test_case.input.split_once("?-") {
+                Some((input, query)) => (input, Some(query)),
+                None => (test_case.input.as_str(), None),
+            };
+            let mut p = match Program::build(input) {
+                Ok(p) => p,
+                Err(e) => return format!("error: {}\n", e),
+            };
+            if let Some(join) = test_case.args.get("join") {
+                p.set_multiway_join(join[0] == "multiway");
+            }
 
             let mut out = String::new();
+            if test_case.directive == "query" {
+                match p.query(query.unwrap()) {
+                    Ok(results) => {
+                        for res in results {
+                            let args: Vec<_> = res.iter().map(|d| d.to_string()).collect();
+                            out.push_str(&format!("{}\n", args.join(", ")));
+                        }
+                    }
+                    Err(e) => out.push_str(&format!("error: {}\n", e)),
+                }
+                return out;
+            }
+            if test_case.directive == "magic" {
+                match p.magic(query.unwrap()) {
+                    Ok(p) => {
+                        for rel in p.relations.values() {
+                            for clause in &rel.clauses {
+                                out.push_str(&format!("{}\n", p.show(clause)));
+                            }
+                        }
+                    }
+                    Err(e) => out.push_str(&format!("error: {}\n", e)),
+                }
+                return out;
+            }
+            if test_case.directive == "plan" {
+                return match p.explain() {
+                    Ok(plan) => plan,
+                    Err(e) => format!("error: {}\n", e),
+                };
+            }
+            if test_case.directive == "check" {
+                for d in p.check() {
+                    out.push_str(&format!("{}: {}\n", d.severity, d));
+                }
+                return out;
+            }
+
             let out_rel = &test_case.args.get("out").unwrap()[0];
-            let results = p.render(out_rel);
+            let mut results = match p.render(out_rel) {
+                Ok(results) => results,
+                Err(e) => return format!("error: {}\n", e),
+            };
+            results.sort();
             for res in results {
                 out.push_str(&format!("{}(", out_rel));
                 let mut sep = "";
@@ -235,3 +1449,364 @@ fn test_datalog() {
         });
     })
 }
+
+#[test]
+fn test_render_all() {
+    let p = Program::build(
+        "edge(1, 2).
+        edge(2, 3).
+        reachable(X, Y) <- edge(X, Y).
+        reachable(X, Z) <- reachable(X, Y), edge(Y, Z).
+        node(X) <- edge(X, Y).
+        node(Y) <- edge(X, Y).",
+    )
+    .unwrap();
+
+    let mut all = p.clone().render_all(&["reachable", "node"]).unwrap();
+    assert_eq!(all.len(), 2);
+    for (rel, rows) in &mut all {
+        let mut expected = p.clone().render(rel).unwrap();
+        expected.sort();
+        rows.sort();
+        assert_eq!(*rows, expected);
+    }
+
+    assert!(p.clone().render_all(&[]).unwrap().is_empty());
+    assert_eq!(
+        p.render_all(&["node", "nope"]).unwrap_err().to_string(),
+        "unknown relation nope"
+    );
+}
+
+#[test]
+fn test_clause() {
+    let var = |s: &str| Expr::Var(s.to_owned());
+    let mut p = Program::new();
+    p.clause(
+        (
+            "edge",
+            vec![Expr::Datum(Datum::Int(1)), Expr::Datum(Datum::Int(2))],
+        ),
+        &[],
+    )
+    .unwrap();
+    p.clause(
+        (
+            "out",
+            vec![var("X"), Expr::Aggregate(Aggregate::Count, "Y".to_owned())],
+        ),
+        &[("edge".to_owned(), vec![var("X"), var("Y")])],
+    )
+    .unwrap();
+    assert_eq!(
+        p.clone().render("out").unwrap(),
+        vec![vec![Datum::Int(1), Datum::Int(1)]]
+    );
+
+    // Rules the parser wouldn't accept are errors rather than panics.
+    let sum = Expr::Arith(ArithOp::Add, Box::new(var("X")), Box::new(var("Y")));
+    let err = p
+        .clause(
+            ("a", vec![sum]),
+            &[("edge".to_owned(), vec![var("X"), var("Y")])],
+        )
+        .unwrap_err();
+    assert_eq!(err.to_string(), "arithmetic is only allowed in constraints");
+    let max = Expr::Aggregate(Aggregate::Max, "Y".to_owned());
+    let err = p
+        .clause(
+            ("a", vec![max.clone(), max]),
+            &[("edge".to_owned(), vec![var("X"), var("Y")])],
+        )
+        .unwrap_err();
+    assert_eq!(err.to_string(), "a can only have one aggregate");
+    assert_eq!(p.relations(), vec!["edge", "out"]);
+}
+
+#[test]
+fn test_instance() {
+    let p = Program::build(
+        "
+        edge(1, 2).
+        reachable(1).
+        reachable(A) <- reachable(B), edge(B, A).
+        ",
+    )
+    .unwrap();
+    let mut i = p.instantiate("reachable").unwrap();
+    let mut contents = i.contents();
+    contents.sort();
+    assert_eq!(contents, vec![vec![Datum::Int(1)], vec![Datum::Int(2)]]);
+
+    let delta = i
+        .insert_fact("edge", vec![Datum::Int(2), Datum::Int(3)])
+        .unwrap();
+    assert_eq!(delta.inserted, vec![vec![Datum::Int(3)]]);
+    assert!(delta.retracted.is_empty());
+
+    let delta = i
+        .retract_fact("edge", &[Datum::Int(1), Datum::Int(2)])
+        .unwrap();
+    let mut retracted = delta.retracted;
+    retracted.sort();
+    assert_eq!(retracted, vec![vec![Datum::Int(2)], vec![Datum::Int(3)]]);
+    assert!(delta.inserted.is_empty());
+
+    assert!(i.insert_fact("nope", vec![]).is_err());
+    let err = Program::build("a(1).").unwrap().instantiate("nope");
+    assert_eq!(err.err().unwrap().to_string(), "unknown relation nope");
+
+    // Facts are converted to the types worked out for undeclared relations.
+    let p = Program::build(
+        "
+        .decl edge(src: uint, dst: uint)
+        edge(1, 2).
+        r(Y) <- start(X), edge(X, Y).
+        ",
+    )
+    .unwrap();
+    let mut i = p.instantiate("r").unwrap();
+    let delta = i.insert_fact("start", vec![Datum::Int(1)]).unwrap();
+    assert_eq!(delta.inserted, vec![vec![Datum::UInt(2)]]);
+}
+
+#[test]
+fn test_instance_negation() {
+    let p = Program::build(
+        "
+        node(1).
+        node(2).
+        node(3).
+        reachable(1).
+        reachable(A) <- reachable(B), edge(B, A).
+        unreachable(A) <- node(A), !reachable(A).
+        ",
+    )
+    .unwrap();
+    let mut i = p.instantiate("unreachable").unwrap();
+    let mut contents = i.contents();
+    contents.sort();
+    assert_eq!(contents, vec![vec![Datum::Int(2)], vec![Datum::Int(3)]]);
+
+    // A new edge can only make things reachable, but that takes them out of
+    // the output.
+    let delta = i
+        .insert_fact("edge", vec![Datum::Int(1), Datum::Int(2)])
+        .unwrap();
+    assert_eq!(delta.retracted, vec![vec![Datum::Int(2)]]);
+    assert!(delta.inserted.is_empty());
+
+    let delta = i.insert_fact("node", vec![Datum::Int(4)]).unwrap();
+    assert_eq!(delta.inserted, vec![vec![Datum::Int(4)]]);
+}
+
+#[test]
+fn test_instance_aggregate() {
+    let p = Program::build(
+        "
+        edge(1, 2).
+        deg(X, count(Y)) <- edge(X, Y).
+        ",
+    )
+    .unwrap();
+    let mut i = p.instantiate("deg").unwrap();
+    assert_eq!(i.contents(), vec![vec![Datum::Int(1), Datum::Int(1)]]);
+
+    let delta = i
+        .insert_fact("edge", vec![Datum::Int(1), Datum::Int(3)])
+        .unwrap();
+    assert_eq!(delta.inserted, vec![vec![Datum::Int(1), Datum::Int(2)]]);
+    assert_eq!(delta.retracted, vec![vec![Datum::Int(1), Datum::Int(1)]]);
+
+    // Taking away the shortest way somewhere falls back to the next one,
+    // rather than to a longer path around the cycle.
+    let p = Program::build(
+        "
+        edge(1, 2, 7). edge(1, 3, 2). edge(3, 2, 1). edge(2, 4, 1). edge(4, 1, 1).
+        dist(1, 0).
+        dist(Y, min(D)) <- dist(X, D1), edge(X, Y, W), D = D1 + W.
+        ",
+    )
+    .unwrap();
+    let mut i = p.instantiate("dist").unwrap();
+    let ints = |row: &[i64]| row.iter().map(|x| Datum::Int(*x)).collect::<Vec<_>>();
+    i.retract_fact("edge", &ints(&[1, 3, 2])).unwrap();
+    let mut contents = i.contents();
+    contents.sort();
+    assert_eq!(contents, vec![ints(&[1, 0]), ints(&[2, 7]), ints(&[4, 8])]);
+}
+
+#[test]
+fn test_retraction() {
+    // Two separate chains, 0 -> ... -> 50 and 100 -> ... -> 150.
+    let mut src = String::new();
+    for i in (0..50).chain(100..150) {
+        src.push_str(&format!("edge({}, {}).\n", i, i + 1));
+    }
+    src.push_str("path(X, Y) <- edge(X, Y).\n");
+    src.push_str("path(X, Z) <- path(X, Y), edge(Y, Z).\n");
+    let mut i = Program::build(&src).unwrap().instantiate("path").unwrap();
+
+    // Only the paths to the end of the second chain go away, and each of them
+    // only had the one derivation to retract. Nothing else is derived again.
+    let before = i.derivations();
+    let delta = i
+        .retract_fact("edge", &[Datum::Int(149), Datum::Int(150)])
+        .unwrap();
+    assert_eq!(delta.retracted.len(), 50);
+    assert!(delta.inserted.is_empty());
+    assert_eq!(i.derivations() - before, 50);
+
+    let rest = src.replace("edge(149, 150).\n", "");
+    let mut expected = Program::build(&rest).unwrap().render("path").unwrap();
+    expected.sort();
+    let mut contents = i.contents();
+    contents.sort();
+    assert_eq!(contents, expected);
+
+    // Tuples that derive each other are removed once nothing else does.
+    let p = Program::build(
+        "
+        reachable(1).
+        edge(1, 2). edge(2, 3). edge(3, 2). edge(4, 3).
+        reachable(A) <- reachable(B), edge(B, A).
+        ",
+    )
+    .unwrap();
+    let mut i = p.instantiate("reachable").unwrap();
+    let delta = i.insert_fact("reachable", vec![Datum::Int(4)]).unwrap();
+    assert_eq!(delta.inserted, vec![vec![Datum::Int(4)]]);
+    let delta = i
+        .retract_fact("edge", &[Datum::Int(1), Datum::Int(2)])
+        .unwrap();
+    assert!(delta.retracted.is_empty() && delta.inserted.is_empty());
+    let delta = i.retract_fact("reachable", &[Datum::Int(4)]).unwrap();
+    let mut retracted = delta.retracted;
+    retracted.sort();
+    assert_eq!(
+        retracted,
+        vec![
+            vec![Datum::Int(2)],
+            vec![Datum::Int(3)],
+            vec![Datum::Int(4)]
+        ]
+    );
+}
+
+#[test]
+fn test_memory_limit() {
+    let mut src = String::new();
+    for i in 0..60 {
+        src.push_str(&format!("edge({}, {}).\n", i, i + 1));
+    }
+    src.push_str("reachable(X, Y) <- edge(X, Y).\n");
+    src.push_str("reachable(X, Z) <- reachable(X, Y), edge(Y, Z).\n");
+    let p = Program::build(&src).unwrap();
+
+    let mut expected = p.clone().instantiate("reachable").unwrap().contents();
+    expected.sort();
+    let mut limited = p.clone();
+    limited.set_memory_limit(Some(4 << 10));
+    let instance = limited.instantiate("reachable").unwrap();
+    let mut rows = instance.contents();
+    rows.sort();
+    assert_eq!(rows, expected);
+
+    let stats = instance.spill_stats();
+    assert!(stats.records > 1000 && stats.runs > 1, "{:?}", stats);
+    assert_eq!(
+        p.instantiate("reachable").unwrap().spill_stats(),
+        SpillStats::default()
+    );
+}
+
+#[test]
+fn test_semi_naive() {
+    use crate::babyflow::Operator;
+    use std::cell::Cell;
+
+    // The way programs used to be evaluated: a single dataflow with each
+    // rule's output wired straight back into its relation, relying on
+    // `distinct` to stop the recursion.
+    fn wired_derivations(mut p: Program) -> usize {
+        let facts = p.take_facts();
+        let mut q = Query::new();
+        let mut inputs = HashMap::new();
+        let mut ops = HashMap::new();
+        for name in p.relation_names() {
+            let (handle, rows) = q.input();
+            let (input, operator): (_, Operator<Row>) = q.merge();
+            q.wire(rows, input.clone());
+            inputs.insert(name, (handle, input));
+            ops.insert(name, operator.distinct().map(|row| (row, 1)));
+        }
+
+        let derivations = Rc::new(Cell::new(0));
+        for (name, rel) in &p.relations {
+            for clause in &rel.clauses {
+                let d = derivations.clone();
+                let derived = p
+                    .compile_clause(
+                        &mut q,
+                        &ops,
+                        &HashMap::new(),
+                        &mut HashMap::new(),
+                        clause,
+                        &HashMap::new(),
+                    )
+                    .unwrap()
+                    .map(move |(row, _)| {
+                        d.set(d.get() + 1);
+                        row
+                    });
+                q.wire(derived, inputs[name].1.clone());
+            }
+        }
+        for (name, rows) in facts {
+            inputs[&name].0.give_iterator(rows);
+        }
+        (*q.df).borrow_mut().run();
+        derivations.get()
+    }
+
+    let programs = [
+        (
+            "path",
+            "
+            edge(1, 2). edge(2, 3). edge(3, 4). edge(4, 1). edge(4, 5).
+            path(A, B) <- edge(A, B).
+            path(A, C) <- path(A, B), edge(B, C).
+            ",
+        ),
+        (
+            "path",
+            "
+            edge(1, 2). edge(2, 3). edge(3, 4). edge(4, 1). edge(4, 5).
+            path(A, B) <- edge(A, B).
+            path(A, C) <- path(A, B), path(B, C).
+            ",
+        ),
+        (
+            "odd",
+            "
+            edge(1, 2). edge(2, 3). edge(1, 3). edge(3, 4).
+            odd(A, B) <- edge(A, B).
+            odd(A, C) <- even(A, B), edge(B, C).
+            even(A, C) <- odd(A, B), edge(B, C).
+            ",
+        ),
+    ];
+    for (out_rel, program) in programs.iter() {
+        let semi_naive = Program::build(program)
+            .unwrap()
+            .instantiate(out_rel)
+            .unwrap();
+        let wired = wired_derivations(Program::build(program).unwrap());
+
+        // The joins in the wired dataflow already only ever match new tuples
+        // against old ones, so both derive each instantiation of a rule body
+        // exactly once.
+        assert!(!semi_naive.contents().is_empty());
+        assert_eq!(semi_naive.derivations(), wired);
+    }
+}
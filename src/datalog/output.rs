@@ -0,0 +1,308 @@
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{self, BufWriter, Write as _},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use anyhow::bail;
+
+use super::{lang::Quoted, Datum, Ident, Instance, Program, Type};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    // `rel(1, a).`, the way they'd be written in a program.
+    Facts,
+    Csv,
+    // An object from each relation's name to a list of its tuples.
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "facts" => Ok(Format::Facts),
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            _ => bail!("unknown format {}, expected facts, csv or json", s),
+        }
+    }
+}
+
+// A file a relation is written to once the program has run.
+#[derive(Debug, Clone)]
+pub(super) struct Output {
+    pub path: PathBuf,
+    pub format: Format,
+}
+
+impl Output {
+    // Without a format, it's guessed from the file's extension.
+    pub fn new(path: PathBuf, format: Option<Format>) -> Self {
+        let format =
+            format.unwrap_or_else(|| match path.extension().and_then(|ext| ext.to_str()) {
+                Some("json") => Format::Json,
+                Some("facts") | Some("dl") => Format::Facts,
+                _ => Format::Csv,
+            });
+        Output { path, format }
+    }
+}
+
+impl Program {
+    // Has the relation written to a file when the program is run.
+    pub fn output(&mut self, rel: &str, path: impl Into<PathBuf>, format: Format) {
+        let name = self.intern(rel);
+        let output = Output::new(path.into(), Some(format));
+        self.relations.entry(name).or_default().outputs.push(output);
+    }
+
+    // The relations that will be written to files, in order.
+    pub fn outputs(&self) -> Vec<String> {
+        let mut names: Vec<_> = self
+            .relations
+            .iter()
+            .filter(|(_, rel)| !rel.outputs.is_empty())
+            .map(|(name, _)| self.name(*name).to_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    // Runs the program once, and writes every relation given to an .output
+    // directive to its file.
+    pub fn run(self) -> anyhow::Result<()> {
+        match self.outputs().first() {
+            Some(rel) => {
+                let rel = rel.clone();
+                self.instantiate(&rel)?.write_outputs()
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl Instance {
+    // Writes the current contents of every relation given to an .output
+    // directive to its file, sorted.
+    pub fn write_outputs(&self) -> anyhow::Result<()> {
+        for (name, rel) in &self.program.relations {
+            for output in &rel.outputs {
+                self.write_output(*name, output)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_output(&self, name: Ident, output: &Output) -> anyhow::Result<()> {
+        let mut rows: Vec<_> = self.relations[&name].iter().collect();
+        rows.sort();
+        let types = self.program.column_types(name);
+        let relation = [(self.program.name(name).to_owned(), types, rows)];
+        let file = match File::create(&output.path) {
+            Ok(file) => file,
+            Err(e) => bail!("can't create {}: {}", output.path.display(), e),
+        };
+        let mut w = BufWriter::new(file);
+        write(&mut w, output.format, &relation)
+            .and_then(|_| w.flush())
+            .map_err(|e| anyhow::anyhow!("can't write {}: {}", output.path.display(), e))
+    }
+}
+
+// A relation's name, the types of its columns where they're known, and its
+// rows.
+pub type Table<R> = (String, Vec<Option<Type>>, Vec<R>);
+
+// Writes out the contents of some relations. In CSV, if there's more than one
+// relation, each row starts with the name of the relation it's from.
+pub fn write<W: io::Write, R: AsRef<[Datum]>>(
+    w: &mut W,
+    format: Format,
+    relations: &[Table<R>],
+) -> io::Result<()> {
+    match format {
+        Format::Facts => {
+            for (name, types, rows) in relations {
+                for row in rows {
+                    writeln!(w, "{}({}).", name, fact_args(row.as_ref(), types))?;
+                }
+            }
+        }
+        Format::Csv => {
+            for (name, _, rows) in relations {
+                for row in rows {
+                    let mut fields: Vec<_> = row
+                        .as_ref()
+                        .iter()
+                        .map(|d| match d {
+                            Datum::Atom(s) => csv_field(s.as_str()),
+                            d => d.to_string(),
+                        })
+                        .collect();
+                    if relations.len() > 1 {
+                        fields.insert(0, csv_field(name));
+                    }
+                    writeln!(w, "{}", fields.join(","))?;
+                }
+            }
+        }
+        Format::Json => {
+            let mut out = String::from("{");
+            for (i, (name, _, rows)) in relations.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write!(out, "\n  {}: [", json_string(name)).unwrap();
+                for (j, row) in rows.iter().enumerate() {
+                    if j > 0 {
+                        out.push(',');
+                    }
+                    let fields: Vec<_> = row.as_ref().iter().map(json_datum).collect();
+                    write!(out, "\n    [{}]", fields.join(", ")).unwrap();
+                }
+                if !rows.is_empty() {
+                    out.push_str("\n  ");
+                }
+                out.push(']');
+            }
+            if !relations.is_empty() {
+                out.push('\n');
+            }
+            out.push('}');
+            writeln!(w, "{}", out)?;
+        }
+    }
+    Ok(())
+}
+
+// The values in a fact, written the way they would be in a program. Values
+// from string columns are always quoted.
+pub(super) fn fact_args(row: &[Datum], types: &[Option<Type>]) -> String {
+    let args: Vec<_> = row
+        .iter()
+        .enumerate()
+        .map(|(i, d)| match types.get(i) {
+            Some(Some(Type::String)) => Quoted(d).to_string(),
+            _ => d.to_string(),
+        })
+        .collect();
+    args.join(", ")
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+fn json_datum(d: &Datum) -> String {
+    match d {
+        Datum::Int(i) => i.to_string(),
+        Datum::UInt(u) => u.to_string(),
+        Datum::Float(x) if x.is_finite() => x.to_string(),
+        Datum::Float(x) => json_string(&x.to_string()),
+        Datum::Bool(b) => b.to_string(),
+        Datum::Atom(s) => json_string(s.as_str()),
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[test]
+fn test_run() {
+    let dir = std::env::temp_dir().join(format!("datalog-test-run-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut p = Program::build(
+        "edge(1, 2).
+        edge(2, 3).
+        reachable(X, Y) <- edge(X, Y).
+        reachable(X, Z) <- reachable(X, Y), edge(Y, Z).
+        .output edge(path=\"edge.facts\")",
+    )
+    .unwrap();
+    // Directives are relative to the working directory.
+    p.relations
+        .values_mut()
+        .flat_map(|rel| &mut rel.outputs)
+        .for_each(|output| output.path = dir.join(&output.path));
+    p.output("reachable", dir.join("reachable.csv"), Format::Csv);
+    p.output("reachable", dir.join("reachable.json"), Format::Json);
+    assert_eq!(p.outputs(), vec!["edge", "reachable"]);
+    p.run().unwrap();
+
+    let read = |name| std::fs::read_to_string(dir.join(name)).unwrap();
+    assert_eq!(read("edge.facts"), "edge(1, 2).\nedge(2, 3).\n");
+    assert_eq!(read("reachable.csv"), "1,2\n1,3\n2,3\n");
+    assert_eq!(
+        read("reachable.json"),
+        "{\n  \"reachable\": [\n    [1, 2],\n    [1, 3],\n    [2, 3]\n  ]\n}\n"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_write() {
+    let relations = vec![
+        (
+            "edge".to_owned(),
+            vec![],
+            vec![
+                vec![Datum::Int(1), Datum::atom("a")],
+                vec![Datum::Int(2), Datum::atom("b,\"c\"")],
+            ],
+        ),
+        ("empty".to_owned(), vec![], vec![]),
+    ];
+    let render = |format, relations: &[_]| {
+        let mut out = Vec::new();
+        write(&mut out, format, relations).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+
+    assert_eq!(
+        render(Format::Facts, &relations),
+        "edge(1, a).\nedge(2, \"b,\\\"c\\\"\").\n"
+    );
+    assert_eq!(
+        render(Format::Csv, &relations[..1]),
+        "1,a\n2,\"b,\"\"c\"\"\"\n"
+    );
+    assert_eq!(
+        render(Format::Csv, &relations),
+        "edge,1,a\nedge,2,\"b,\"\"c\"\"\"\n"
+    );
+    assert_eq!(
+        render(Format::Json, &relations),
+        "{\n  \"edge\": [\n    [1, \"a\"],\n    [2, \"b,\\\"c\\\"\"]\n  ],\n  \"empty\": []\n}\n"
+    );
+
+    // Values from string columns are quoted even if they could be bare, so
+    // they're read back as strings.
+    let names = vec![(
+        "name".to_owned(),
+        vec![Some(Type::String), None],
+        vec![vec![Datum::atom("a"), Datum::atom("b")]],
+    )];
+    assert_eq!(render(Format::Facts, &names), "name(\"a\", b).\n");
+}
@@ -1,6 +1,50 @@
-use anyhow::{anyhow, bail};
+use std::fmt;
 
-use super::lang::{Datum, Expr};
+use super::{
+    lang::{Aggregate, ArithOp, CmpOp, Datum, Expr, Type},
+    output::Format,
+};
+
+// Where and why parsing failed. Lines and columns start at 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub col: usize,
+    // The line the error is on.
+    pub snippet: String,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}:{}: {}", self.line, self.col, self.message)?;
+        writeln!(f, "  {}", self.snippet)?;
+        write!(f, "  {}^", " ".repeat(self.col - 1))
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// Every error found in the input, in order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseErrors {
+    pub errors: Vec<ParseError>,
+}
+
+impl fmt::Display for ParseErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut sep = "";
+        for e in &self.errors {
+            write!(f, "{}{}", sep, e)?;
+            sep = "\n";
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ParseErrors {}
+
+type Result<T> = std::result::Result<T, ParseError>;
 
 #[derive(Debug, Clone)]
 pub struct Predicate {
@@ -8,15 +52,47 @@ pub struct Predicate {
     pub args: Vec<Expr>,
 }
 
+#[derive(Debug, Clone)]
+pub enum Literal {
+    Atom(Predicate),
+    Negated(Predicate),
+    Constraint(Expr, CmpOp, Expr),
+}
+
 #[derive(Debug, Clone)]
 pub struct Clause {
     pub head: Predicate,
-    pub body: Vec<Predicate>,
+    pub body: Vec<Literal>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Directive {
+    // `.input edge(path="edge.csv", delimiter=",")`, to read a relation's
+    // facts from a file.
+    Input {
+        rel: String,
+        path: String,
+        delimiter: Option<char>,
+    },
+    // `.output reachable(path="reachable.json", format="json")`, to write a
+    // relation to a file once the program has run.
+    Output {
+        rel: String,
+        path: String,
+        format: Option<Format>,
+    },
+    // `.decl edge(src: int, dst: int)`, giving the types of a relation's
+    // columns.
+    Decl {
+        rel: String,
+        columns: Vec<(String, Type)>,
+    },
 }
 
 #[derive(Debug, Clone)]
 pub struct Syntax {
     pub clauses: Vec<Clause>,
+    pub directives: Vec<Directive>,
 }
 
 #[derive(Debug)]
@@ -26,15 +102,94 @@ struct Parser {
 }
 
 impl Parser {
-    fn parse(&mut self) -> anyhow::Result<Syntax> {
+    // Parses as many clauses as it can, skipping to the end of any clause
+    // with an error in it so that later errors are reported too.
+    fn parse(&mut self) -> std::result::Result<Syntax, ParseErrors> {
         let mut clauses = Vec::new();
+        let mut directives = Vec::new();
+        let mut errors = Vec::new();
         self.munch();
         while self.idx < self.chars.len() {
-            clauses.push(self.clause()?);
+            // Directives don't end with a period, so they run to the end of
+            // the line.
+            let (result, end) = if self.peek() == Some('.') {
+                (self.directive().map(|d| directives.push(d)), '\n')
+            } else {
+                (self.clause().map(|c| clauses.push(c)), '.')
+            };
+            if let Err(e) = result {
+                errors.push(e);
+                self.recover(end);
+            }
             self.munch();
         }
 
-        Ok(Syntax { clauses })
+        if errors.is_empty() {
+            Ok(Syntax {
+                clauses,
+                directives,
+            })
+        } else {
+            Err(ParseErrors { errors })
+        }
+    }
+
+    // Skips past the next `end` that isn't inside a string or a number, like
+    // the periods in `"a.b"` or `1.5`.
+    fn recover(&mut self, end: char) {
+        while let Some(c) = self.peek() {
+            if c == '"' {
+                self.idx += 1;
+                while let Some(c) = self.peek() {
+                    self.idx += if c == '\\' { 2 } else { 1 };
+                    if c == '"' {
+                        break;
+                    }
+                }
+            } else if c.is_ascii_digit() {
+                let _ = self.number();
+            } else {
+                self.idx += 1;
+                if c == end {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn error(&self, message: String) -> ParseError {
+        // Point just past the last thing in the input, rather than at
+        // whatever whitespace follows it.
+        let idx = if self.idx >= self.chars.len() {
+            self.chars
+                .iter()
+                .rposition(|c| !c.is_ascii_whitespace())
+                .map_or(0, |i| i + 1)
+        } else {
+            self.idx
+        };
+        let start = self.chars[..idx]
+            .iter()
+            .rposition(|c| *c == '\n')
+            .map_or(0, |i| i + 1);
+        let end = self.chars[idx..]
+            .iter()
+            .position(|c| *c == '\n')
+            .map_or(self.chars.len(), |i| idx + i);
+        ParseError {
+            line: self.chars[..start].iter().filter(|c| **c == '\n').count() + 1,
+            col: idx - start + 1,
+            snippet: self.chars[start..end].iter().collect(),
+            message,
+        }
+    }
+
+    // What's at the current position, for error messages.
+    fn found(&self) -> String {
+        match self.peek() {
+            Some(c) => format!("found {}", c),
+            None => "reached the end of the input".to_owned(),
+        }
     }
 
     fn munch(&mut self) {
@@ -43,7 +198,7 @@ impl Parser {
         }
     }
 
-    fn clause(&mut self) -> anyhow::Result<Clause> {
+    fn clause(&mut self) -> Result<Clause> {
         let head = self.predicate()?;
         self.munch();
         if self.peek() == Some('.') {
@@ -55,30 +210,341 @@ impl Parser {
         } else {
             self.expect("<-")?;
             self.munch();
-            let mut body = vec![self.predicate()?];
-            self.munch();
-            while self.peek() == Some(',') {
-                self.expect(",")?;
-                self.munch();
-                body.push(self.predicate()?);
-                self.munch();
-            }
+            let body = self.body()?;
             self.expect(".")?;
             Ok(Clause { head, body })
         }
     }
 
-    fn predicate(&mut self) -> anyhow::Result<Predicate> {
-        let name = self.word()?;
+    fn directive(&mut self) -> Result<Directive> {
+        self.expect(".")?;
+        let start = self.idx;
+        let kind = self.word()?;
+        if !matches!(kind.as_str(), "input" | "output" | "decl") {
+            self.idx = start;
+            return Err(self.error(format!("unknown directive .{}", kind)));
+        }
         self.munch();
+        let rel = self.word()?;
+        if rel.is_empty() {
+            return Err(self.error(format!("expected a relation but {}", self.found())));
+        }
+        self.munch();
+        if kind == "decl" {
+            let columns = self.columns()?;
+            return Ok(Directive::Decl { rel, columns });
+        }
+        let keys = if kind == "input" {
+            ["path", "delimiter"]
+        } else {
+            ["path", "format"]
+        };
+
+        let mut path = None;
+        let mut delimiter = None;
+        let mut format = None;
+        for (at, key, value) in self.params()? {
+            let problem = match key.as_str() {
+                _ if !keys.contains(&key.as_str()) => Some(format!(
+                    "unknown parameter {}, expected {}",
+                    key,
+                    keys.join(" or ")
+                )),
+                "path" => {
+                    path = Some(value);
+                    None
+                }
+                "delimiter" => {
+                    let mut chars = value.chars();
+                    delimiter = chars.next();
+                    match (delimiter, chars.next()) {
+                        (Some(_), None) => None,
+                        _ => Some("the delimiter has to be a single character".to_owned()),
+                    }
+                }
+                _ => match value.parse::<Format>() {
+                    Ok(f) => {
+                        format = Some(f);
+                        None
+                    }
+                    Err(e) => Some(e.to_string()),
+                },
+            };
+            if let Some(message) = problem {
+                self.idx = at;
+                return Err(self.error(message));
+            }
+        }
+        let path = match path {
+            Some(path) => path,
+            None => return Err(self.error(format!(".{} {} needs a path", kind, rel))),
+        };
+        if kind == "input" {
+            Ok(Directive::Input {
+                rel,
+                path,
+                delimiter,
+            })
+        } else {
+            Ok(Directive::Output { rel, path, format })
+        }
+    }
+
+    // `(name: type, ...)`.
+    fn columns(&mut self) -> Result<Vec<(String, Type)>> {
+        self.expect("(")?;
+        let mut columns = Vec::new();
+        loop {
+            self.munch();
+            let name = self.word()?;
+            if name.is_empty() {
+                return Err(self.error(format!("expected a column but {}", self.found())));
+            }
+            self.munch();
+            self.expect(":")?;
+            self.munch();
+            let start = self.idx;
+            let name_of_type = self.word()?;
+            let ty = match Type::from_name(&name_of_type) {
+                Some(ty) => ty,
+                None => {
+                    self.idx = start;
+                    return Err(self.error(format!(
+                        "unknown type {}, expected int, uint, float, bool or string",
+                        name_of_type
+                    )));
+                }
+            };
+            columns.push((name, ty));
+            self.munch();
+            if self.eat(")") {
+                return Ok(columns);
+            }
+            if !self.eat(",") {
+                return Err(self.error(format!("expected , or ) but {}", self.found())));
+            }
+        }
+    }
+
+    // `(key="value", ...)`, along with where each key is.
+    fn params(&mut self) -> Result<Vec<(usize, String, String)>> {
         self.expect("(")?;
         self.munch();
-        let mut args = vec![self.expr()?];
+        let mut params = Vec::new();
+        while !self.eat(")") {
+            if !params.is_empty() {
+                if !self.eat(",") {
+                    return Err(self.error(format!("expected , or ) but {}", self.found())));
+                }
+                self.munch();
+            }
+            let at = self.idx;
+            let key = self.word()?;
+            if key.is_empty() {
+                return Err(self.error(format!("expected a parameter but {}", self.found())));
+            }
+            self.munch();
+            self.expect("=")?;
+            self.munch();
+            params.push((at, key, self.string()?));
+            self.munch();
+        }
+        Ok(params)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        if !self.eat("\"") {
+            return Err(self.error(format!("expected a string but {}", self.found())));
+        }
+        let mut out = String::new();
+        loop {
+            let c = match self.peek() {
+                Some('"') => {
+                    self.idx += 1;
+                    return Ok(out);
+                }
+                Some('\\') => {
+                    self.idx += 1;
+                    match self.peek() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some(c @ ('"' | '\\')) => c,
+                        Some(c) => return Err(self.error(format!("unknown escape \\{}", c))),
+                        None => break,
+                    }
+                }
+                Some(c) => c,
+                None => break,
+            };
+            out.push(c);
+            self.idx += 1;
+        }
+        Err(self.error("expected \" but reached the end of the input".to_owned()))
+    }
+
+    fn body(&mut self) -> Result<Vec<Literal>> {
+        let mut body = vec![self.literal()?];
         self.munch();
         while self.peek() == Some(',') {
             self.expect(",")?;
             self.munch();
-            args.push(self.expr()?);
+            body.push(self.literal()?);
+            self.munch();
+        }
+        Ok(body)
+    }
+
+    // A rule body on its own, optionally ending with a period.
+    fn query(&mut self) -> Result<Vec<Literal>> {
+        self.munch();
+        let body = self.body()?;
+        if self.eat(".") {
+            self.munch();
+        }
+        if self.idx < self.chars.len() {
+            return Err(self.error(format!("expected , or . but {}", self.found())));
+        }
+        Ok(body)
+    }
+
+    fn literal(&mut self) -> Result<Literal> {
+        if self.peek() == Some('!') {
+            self.expect("!")?;
+            self.munch();
+            return Ok(Literal::Negated(self.predicate()?));
+        }
+        let start = self.idx;
+        let name = self.word()?;
+        self.munch();
+        // `not` is only a keyword if it's followed by a predicate.
+        if name == "not" && self.peek().is_some_and(|c| c.is_alphabetic()) {
+            Ok(Literal::Negated(self.predicate()?))
+        } else if !name.is_empty()
+            && name.starts_with(char::is_lowercase)
+            && self.peek() == Some('(')
+        {
+            Ok(Literal::Atom(self.predicate_named(name)?))
+        } else {
+            self.idx = start;
+            self.constraint()
+        }
+    }
+
+    fn constraint(&mut self) -> Result<Literal> {
+        let lhs = self.term()?;
+        self.munch();
+        let op = if self.eat("!=") {
+            CmpOp::Ne
+        } else if self.eat("<=") {
+            CmpOp::Le
+        } else if self.eat(">=") {
+            CmpOp::Ge
+        } else if self.eat("=") {
+            CmpOp::Eq
+        } else if self.eat("<") {
+            CmpOp::Lt
+        } else if self.eat(">") {
+            CmpOp::Gt
+        } else {
+            return Err(self.error(format!("expected a comparison but {}", self.found())));
+        };
+        self.munch();
+        let rhs = self.term()?;
+        Ok(Literal::Constraint(lhs, op, rhs))
+    }
+
+    // Sums of products of expressions.
+    fn term(&mut self) -> Result<Expr> {
+        let mut lhs = self.factor()?;
+        loop {
+            self.munch();
+            let op = if self.eat("+") {
+                ArithOp::Add
+            } else if self.eat("-") {
+                ArithOp::Sub
+            } else {
+                return Ok(lhs);
+            };
+            self.munch();
+            lhs = Expr::Arith(op, Box::new(lhs), Box::new(self.factor()?));
+        }
+    }
+
+    fn factor(&mut self) -> Result<Expr> {
+        let mut lhs = self.operand()?;
+        loop {
+            self.munch();
+            let op = if self.eat("*") {
+                ArithOp::Mul
+            } else if self.eat("/") {
+                ArithOp::Div
+            } else if self.eat("%") {
+                ArithOp::Mod
+            } else {
+                return Ok(lhs);
+            };
+            self.munch();
+            lhs = Expr::Arith(op, Box::new(lhs), Box::new(self.operand()?));
+        }
+    }
+
+    fn operand(&mut self) -> Result<Expr> {
+        if self.eat("(") {
+            self.munch();
+            let e = self.term()?;
+            self.munch();
+            self.expect(")")?;
+            Ok(e)
+        } else {
+            let start = self.idx;
+            match self.expr()? {
+                Expr::Aggregate(..) => {
+                    self.idx = start;
+                    Err(self.error("aggregates are only allowed in the heads of rules".to_owned()))
+                }
+                e => Ok(e),
+            }
+        }
+    }
+
+    // Consumes `s` if it's next.
+    fn eat(&mut self, s: &str) -> bool {
+        let end = self.idx + s.chars().count();
+        if end <= self.chars.len() && self.chars[self.idx..end].iter().copied().eq(s.chars()) {
+            self.idx = end;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn predicate(&mut self) -> Result<Predicate> {
+        let name = self.word()?;
+        if name.is_empty() {
+            return Err(self.error(format!("expected a predicate but {}", self.found())));
+        }
+        self.munch();
+        self.predicate_named(name)
+    }
+
+    fn predicate_named(&mut self, name: String) -> Result<Predicate> {
+        self.expect("(")?;
+        self.munch();
+        let mut args = Vec::new();
+        loop {
+            let start = self.idx;
+            let arg = self.expr()?;
+            let aggregated = |e: &Expr| matches!(e, Expr::Aggregate(..));
+            if aggregated(&arg) && args.iter().any(aggregated) {
+                self.idx = start;
+                return Err(self.error(format!("{} can only have one aggregate", name)));
+            }
+            args.push(arg);
+            self.munch();
+            if self.peek() != Some(',') {
+                break;
+            }
+            self.expect(",")?;
             self.munch();
         }
         self.expect(")")?;
@@ -93,41 +559,115 @@ impl Parser {
         }
     }
 
-    fn expr(&mut self) -> anyhow::Result<Expr> {
+    fn expr(&mut self) -> Result<Expr> {
         if self.idx >= self.chars.len() {
-            bail!("expected expr");
+            return Err(
+                self.error("expected an expression but reached the end of the input".to_owned())
+            );
         }
         if self.chars[self.idx].is_alphabetic() {
             let name = self.word()?;
             if name.chars().next().unwrap().is_uppercase() {
                 Ok(Expr::Var(name))
+            } else if self.peek() == Some('(') {
+                let start = self.idx;
+                let agg = match Aggregate::from_name(&name) {
+                    Some(agg) => agg,
+                    None => {
+                        self.idx -= name.chars().count();
+                        let e = self.error(format!("unknown aggregate {}", name));
+                        self.idx = start;
+                        return Err(e);
+                    }
+                };
+                self.expect("(")?;
+                self.munch();
+                let var = self.word()?;
+                if !var.chars().next().is_some_and(|c| c.is_uppercase()) {
+                    return Err(self.error(format!("expected a variable to {}", name)));
+                }
+                self.munch();
+                self.expect(")")?;
+                Ok(Expr::Aggregate(agg, var))
+            } else if name == "true" || name == "false" {
+                Ok(Expr::Datum(Datum::Bool(name == "true")))
             } else {
-                Ok(Expr::Datum(Datum::Atom(name)))
+                Ok(Expr::Datum(Datum::atom(&name)))
             }
-        } else if self.chars[self.idx].is_numeric() {
-            let mut s = String::new();
-            while self.idx < self.chars.len() && self.chars[self.idx].is_numeric() {
-                s.push(self.chars[self.idx]);
-                self.idx += 1;
+        } else if self.chars[self.idx] == '"' {
+            Ok(Expr::Datum(Datum::atom(&self.string()?)))
+        } else if self.chars[self.idx].is_ascii_digit()
+            || self.chars[self.idx] == '-' && self.digit_at(self.idx + 1)
+        {
+            self.number()
+        } else {
+            Err(self.error(format!("expected an expression but {}", self.found())))
+        }
+    }
+
+    fn digit_at(&self, idx: usize) -> bool {
+        idx < self.chars.len() && self.chars[idx].is_ascii_digit()
+    }
+
+    // An integer like `-12`, or a float like `1.5` or `2.5e-3`.
+    fn number(&mut self) -> Result<Expr> {
+        let start = self.idx;
+        let digits = |p: &mut Self| {
+            while p.digit_at(p.idx) {
+                p.idx += 1;
             }
-            Ok(Expr::Datum(Datum::Int(s.parse()?)))
+        };
+        self.eat("-");
+        digits(self);
+        let mut float = false;
+        // A period is only part of the number if a digit follows it, since
+        // otherwise it ends the clause.
+        if self.peek() == Some('.') && self.digit_at(self.idx + 1) {
+            self.idx += 1;
+            digits(self);
+            float = true;
+        }
+        if matches!(self.peek(), Some('e' | 'E')) {
+            let before = self.idx;
+            self.idx += 1;
+            self.eat("-");
+            if self.digit_at(self.idx) {
+                digits(self);
+                float = true;
+            } else {
+                self.idx = before;
+            }
+        }
+
+        let s: String = self.chars[start..self.idx].iter().collect();
+        let parsed = if float {
+            s.parse().map(Datum::Float).map_err(|e| e.to_string())
         } else {
-            Err(anyhow!("couldn't parse from {}", self.chars[self.idx]))
+            s.parse().map(Datum::Int).map_err(|e| e.to_string())
+        };
+        match parsed {
+            Ok(d) => Ok(Expr::Datum(d)),
+            Err(e) => {
+                let end = std::mem::replace(&mut self.idx, start);
+                let kind = if float { "float" } else { "integer" };
+                let err = self.error(format!("invalid {} {}: {}", kind, s, e));
+                self.idx = end;
+                Err(err)
+            }
         }
     }
 
-    fn expect(&mut self, s: &str) -> anyhow::Result<()> {
+    fn expect(&mut self, s: &str) -> Result<()> {
         for ch in s.chars() {
             if self.idx >= self.chars.len() || self.chars[self.idx] != ch {
-                println!("{:?} {}", self, s);
-                bail!("expected {}", s);
+                return Err(self.error(format!("expected {} but {}", s, self.found())));
             }
             self.idx += 1;
         }
         Ok(())
     }
 
-    fn word(&mut self) -> anyhow::Result<String> {
+    fn word(&mut self) -> Result<String> {
         let mut out = String::new();
         while self.idx < self.chars.len() && self.chars[self.idx].is_alphanumeric() {
             out.push(self.chars[self.idx]);
@@ -138,7 +678,7 @@ impl Parser {
     }
 }
 
-pub fn parse(s: &str) -> anyhow::Result<Syntax> {
+pub fn parse(s: &str) -> std::result::Result<Syntax, ParseErrors> {
     let mut p = Parser {
         idx: 0,
         chars: s.chars().collect(),
@@ -147,11 +687,52 @@ pub fn parse(s: &str) -> anyhow::Result<Syntax> {
     p.parse()
 }
 
+pub fn parse_query(s: &str) -> std::result::Result<Vec<Literal>, ParseErrors> {
+    let mut p = Parser {
+        idx: 0,
+        chars: s.chars().collect(),
+    };
+
+    p.query().map_err(|e| ParseErrors { errors: vec![e] })
+}
+
 #[test]
 fn test_parse() {
     use datadriven::walk;
 
     walk("src/testdata/parse", |f| {
-        f.run(|test_case| format!("{:#?}\n", parse(&test_case.input).unwrap()))
+        f.run(|test_case| match parse(&test_case.input) {
+            Ok(syntax) => format!("{:#?}\n", syntax),
+            Err(e) => format!("{}\n", e),
+        })
     })
 }
+
+#[test]
+fn test_parse_errors() {
+    let errors = parse("a(1).\nb(X) <- c(X.\nd(2).\ne(").unwrap_err().errors;
+    assert_eq!(
+        errors,
+        vec![
+            ParseError {
+                line: 2,
+                col: 12,
+                snippet: "b(X) <- c(X.".to_owned(),
+                message: "expected ) but found .".to_owned(),
+            },
+            ParseError {
+                line: 4,
+                col: 3,
+                snippet: "e(".to_owned(),
+                message: "expected an expression but reached the end of the input".to_owned(),
+            },
+        ]
+    );
+
+    // Periods in strings and numbers don't end the clause being skipped.
+    let errors = parse("p(X Y, \"a.b\").\nq(X Y, 1.5).\nr(")
+        .unwrap_err()
+        .errors;
+    let at: Vec<_> = errors.iter().map(|e| (e.line, e.col)).collect();
+    assert_eq!(at, vec![(1, 5), (2, 5), (3, 3)]);
+}
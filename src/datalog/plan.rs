@@ -0,0 +1,220 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::bail;
+
+use super::{magic::bind, Clause, Ident, Program, Row, Severity};
+
+// A step in joining a rule's body: the positive predicate to join in next,
+// the variables it's joined on, and about how many of its tuples each row
+// so far will match.
+#[derive(Debug, Clone)]
+pub(super) struct Step {
+    pub idx: usize,
+    pub on: Vec<Ident>,
+    pub estimate: usize,
+}
+
+impl Program {
+    // Picks the order to join the positive predicates of a rule's body in.
+    //
+    // It's greedy: each step takes the predicate expected to match the fewest
+    // tuples, given the relations' sizes and which of its arguments are
+    // already known. Every known argument is guessed to cut the matches down
+    // by a factor of 10. Predicates that share a variable with what's been
+    // joined so far always come before ones that don't, so there's only a
+    // cross product if the body really is disconnected. Ties go to whichever
+    // was written first.
+    pub(super) fn plan(&self, clause: &Clause, sizes: &HashMap<Ident, usize>) -> Vec<Step> {
+        let mut bound = HashSet::new();
+        bind(&mut bound, &clause.constraints);
+        let mut todo: Vec<usize> = (0..clause.body.len())
+            .filter(|idx| !clause.body[*idx].negated)
+            .collect();
+
+        let mut steps: Vec<Step> = Vec::new();
+        while !todo.is_empty() {
+            let start = bound.is_empty();
+            let (pos, step) = todo
+                .iter()
+                .map(|idx| {
+                    let pred = &clause.body[*idx];
+                    let mut on = Vec::new();
+                    for (_, v) in &pred.variables {
+                        if bound.contains(v) && !on.contains(v) {
+                            on.push(*v);
+                        }
+                    }
+                    let mut estimate = sizes.get(&pred.name).copied().unwrap_or(0).max(1);
+                    for _ in 0..pred.constants.len() + on.len() {
+                        estimate = estimate.div_ceil(10);
+                    }
+                    Step {
+                        idx: *idx,
+                        on,
+                        estimate,
+                    }
+                })
+                .enumerate()
+                .min_by_key(|(_, step)| {
+                    // Predicates without variables can only ever filter.
+                    let cross = step.on.is_empty() && !clause.body[step.idx].variables.is_empty();
+                    (!start && cross, step.estimate)
+                })
+                .unwrap();
+            todo.remove(pos);
+            bound.extend(clause.body[step.idx].variables.iter().map(|(_, v)| *v));
+            bind(&mut bound, &clause.constraints);
+            steps.push(step);
+        }
+        steps
+    }
+
+    // Rough sizes for every relation, given the facts they start with. A
+    // rule is guessed to derive about as many tuples as the biggest relation
+    // it reads. Strata are estimated in the order they're computed, so the
+    // relations a rule reads have estimates already, except for the ones in
+    // its own stratum, which only count their facts.
+    pub(super) fn estimates(&self, facts: &HashMap<Ident, HashSet<Row>>) -> HashMap<Ident, usize> {
+        let mut sizes: HashMap<Ident, usize> = self
+            .relation_names()
+            .into_iter()
+            .map(|name| (name, facts.get(&name).map_or(0, |rows| rows.len())))
+            .collect();
+        for stratum in self.strata().unwrap_or_default() {
+            for name in &stratum {
+                let derived: usize = self.relations[name]
+                    .clauses
+                    .iter()
+                    .map(|clause| {
+                        clause
+                            .body
+                            .iter()
+                            .filter(|pred| !pred.negated)
+                            .map(|pred| sizes.get(&pred.name).copied().unwrap_or(0))
+                            .max()
+                            .unwrap_or(1)
+                    })
+                    .sum();
+                *sizes.entry(*name).or_default() += derived;
+            }
+        }
+        sizes
+    }
+
+    // Describes the order each rule's body will be joined in, like
+    //
+    //   tri(A, B, C) <- edge(A, B), edge(B, C), edge(A, C).
+    //     scan edge(A, B) ~4
+    //     join edge(B, C) on B ~1
+    //     join edge(A, C) on A, C ~1
+    //
+    // where the numbers are how many tuples each step is expected to match.
+    // Cyclic bodies joined all at once get a single line, with the order the
+    // variables are bound in.
+    pub fn explain(mut self) -> anyhow::Result<String> {
+        if let Some(d) = self
+            .check()
+            .into_iter()
+            .find(|d| d.severity == Severity::Error)
+        {
+            bail!("{}", d);
+        }
+        self.load_inputs()?;
+        self.coerce()?;
+        let facts = self.take_facts();
+        let sizes = self.estimates(&facts);
+
+        let mut out = String::new();
+        for stratum in self.strata()? {
+            for name in stratum {
+                for clause in &self.relations[&name].clauses {
+                    out.push_str(&format!("{}\n", self.show(clause)));
+                    let steps = self.plan(clause, &sizes);
+                    if self.multiway_join && cyclic(clause) {
+                        let preds: Vec<_> = steps
+                            .iter()
+                            .map(|step| self.show_predicate(&clause.body[step.idx]))
+                            .collect();
+                        let vars: Vec<_> = order(clause, &steps)
+                            .into_iter()
+                            .map(|v| self.name(*v))
+                            .collect();
+                        out.push_str(&format!(
+                            "  multiway join {} by {}\n",
+                            preds.join(", "),
+                            vars.join(", ")
+                        ));
+                    } else {
+                        for (i, step) in steps.iter().enumerate() {
+                            let pred = self.show_predicate(&clause.body[step.idx]);
+                            let on: Vec<_> = step.on.iter().map(|v| self.name(*v)).collect();
+                            let line = match (i, on.is_empty()) {
+                                (0, true) => format!("scan {}", pred),
+                                (_, true) => format!("cross join {}", pred),
+                                _ => format!("join {} on {}", pred, on.join(", ")),
+                            };
+                            out.push_str(&format!("  {} ~{}\n", line, step.estimate));
+                        }
+                    }
+                    for pred in clause.body.iter().filter(|pred| pred.negated) {
+                        out.push_str(&format!("  antijoin {}\n", self.show_predicate(pred)));
+                    }
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+// The variables of a rule's positive predicates, in the order the plan binds
+// them.
+pub(super) fn order<'a>(clause: &'a Clause, steps: &[Step]) -> Vec<&'a Ident> {
+    let mut order = Vec::new();
+    for step in steps {
+        for (_, v) in &clause.body[step.idx].variables {
+            if !order.contains(&v) {
+                order.push(v);
+            }
+        }
+    }
+    order
+}
+
+// Whether the positive predicates of a rule's body join up in a cycle, like
+// the three edges of a triangle. This is GYO reduction: variables that only
+// one predicate has are dropped, and so are predicates whose variables some
+// other predicate has too, and the body is acyclic if that leaves at most
+// one.
+pub(super) fn cyclic(clause: &Clause) -> bool {
+    let mut preds: Vec<HashSet<Ident>> = clause
+        .body
+        .iter()
+        .filter(|pred| !pred.negated)
+        .map(|pred| pred.variables.iter().map(|(_, v)| *v).collect())
+        .collect();
+    loop {
+        let before: usize = preds.len() + preds.iter().map(|vars| vars.len()).sum::<usize>();
+        let mut counts: HashMap<Ident, usize> = HashMap::new();
+        for v in preds.iter().flatten() {
+            *counts.entry(*v).or_default() += 1;
+        }
+        for vars in &mut preds {
+            vars.retain(|v| counts[v] > 1);
+        }
+        let mut i = 0;
+        while i < preds.len() {
+            if (0..preds.len()).any(|j| j != i && preds[i].is_subset(&preds[j])) {
+                preds.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+        if preds.len() <= 1 {
+            return false;
+        }
+        let after: usize = preds.len() + preds.iter().map(|vars| vars.len()).sum::<usize>();
+        if after == before {
+            return true;
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+
+use anyhow::bail;
+
+use super::{output::fact_args, Datum, Instance, Program, Severity};
+
+pub const HELP: &str = "\
+Enter facts and rules to add them to the program, like
+  edge(1, 2).
+  reachable(X, Y) <- edge(X, Y).
+and ask for what's in a relation with
+  ?- reachable(1, Y).
+Commands:
+  .relations  list every relation and how many tuples are in it
+  .undo       remove the last facts or rules that were entered
+  .input rel(path=\"rel.csv\")
+              read facts for rel from a file
+  .decl rel(a: int, b: string)
+              declare the types of rel's columns
+  .help       show this message";
+
+// An interactive session, which builds up a program a line at a time.
+//
+// The program is only compiled when something asks for its results. After
+// that, facts are inserted into the running Instance, so that only what they
+// newly derive is computed. New rules, and undoing anything, mean the program
+// has to be compiled again the next time it's needed.
+pub struct Session {
+    // Each line that was added to the program, so they can be undone.
+    history: Vec<String>,
+    // Every line in the history, parsed, so each new line is all that has to
+    // be parsed when it's added.
+    program: Program,
+    instance: Option<Instance>,
+    builds: usize,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Session {
+            history: Vec::new(),
+            program: Program::new(),
+            instance: None,
+            builds: 0,
+        }
+    }
+
+    // Responds to a line of input, returning whatever should be shown.
+    pub fn handle(&mut self, line: &str) -> anyhow::Result<String> {
+        let line = line.trim();
+        if let Some(q) = line.strip_prefix("?-") {
+            return self.query(q);
+        }
+        match line {
+            "" => Ok(String::new()),
+            ".relations" => self.list(),
+            ".undo" => self.undo(),
+            ".help" => Ok(format!("{}\n", HELP)),
+            _ if line.starts_with(".input ") || line.starts_with(".decl ") => self.add(line),
+            _ if line.starts_with('.') => bail!("unknown command {}, try .help", line),
+            _ => self.add(line),
+        }
+    }
+
+    // How many times the program has been compiled.
+    pub fn builds(&self) -> usize {
+        self.builds
+    }
+
+    // Parses the history from scratch, for when a line has to be taken back
+    // out of the program.
+    fn rebuild(&mut self) -> anyhow::Result<()> {
+        self.program = Program::build(&self.history.join("\n"))?;
+        Ok(())
+    }
+
+    // Adds a line to the program, failing if it no longer makes sense.
+    fn extend(&mut self, line: &str) -> anyhow::Result<()> {
+        self.program.add(line)?;
+        if let Some(d) = self
+            .program
+            .check()
+            .into_iter()
+            .find(|d| d.severity == Severity::Error)
+        {
+            bail!("{}", d);
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, line: &str) -> anyhow::Result<String> {
+        let mut added = Program::build(line)?;
+
+        // Make sure the program still makes sense before accepting the line.
+        if let Err(e) = self.extend(line) {
+            self.rebuild()?;
+            return Err(e);
+        }
+        self.history.push(line.to_owned());
+
+        let facts = added.take_facts();
+        // Directives, like a .decl changing how facts are typed, mean the
+        // program has to be compiled again.
+        let only_facts = added.relations.values().all(|rel| {
+            rel.clauses.is_empty()
+                && rel.inputs.is_empty()
+                && rel.outputs.is_empty()
+                && rel.decl.is_none()
+        });
+        if let Some(instance) = &mut self.instance {
+            let incremental = only_facts
+                && facts.keys().all(|rel| {
+                    let name = added.name(*rel);
+                    instance.relation(name).is_ok() && {
+                        let id = instance.program.idents[name];
+                        instance.program.aggregate(id).is_none()
+                    }
+                });
+            if incremental {
+                for (rel, rows) in facts {
+                    for row in rows {
+                        instance.insert_fact(added.name(rel), row.to_vec())?;
+                    }
+                }
+            } else {
+                self.instance = None;
+            }
+        }
+        Ok(String::new())
+    }
+
+    fn undo(&mut self) -> anyhow::Result<String> {
+        match self.history.pop() {
+            Some(line) => {
+                self.rebuild()?;
+                self.instance = None;
+                Ok(format!("removed {}\n", line))
+            }
+            None => bail!("nothing to undo"),
+        }
+    }
+
+    fn instance(&mut self) -> anyhow::Result<Option<&Instance>> {
+        if self.instance.is_none() {
+            let program = self.program.clone();
+            let out_rel = match program.relations().into_iter().next() {
+                Some(rel) => rel,
+                None => return Ok(None),
+            };
+            self.instance = Some(program.instantiate(&out_rel)?);
+            self.builds += 1;
+        }
+        Ok(self.instance.as_ref())
+    }
+
+    fn list(&mut self) -> anyhow::Result<String> {
+        let instance = match self.instance()? {
+            Some(instance) => instance,
+            None => return Ok(String::new()),
+        };
+        let mut out = String::new();
+        for rel in instance.program.relations() {
+            let n = instance.contents_of(&rel)?.len();
+            out.push_str(&format!("{} ({} tuples)\n", rel, n));
+        }
+        Ok(out)
+    }
+
+    fn query(&mut self, q: &str) -> anyhow::Result<String> {
+        let q = q.trim();
+        let q = Program::build(&format!("{}{}", q, if q.ends_with('.') { "" } else { "." }))?;
+        let clause = match q.relations.values().flat_map(|rel| &rel.clauses).next() {
+            Some(clause)
+                if clause.body.is_empty()
+                    && clause.constraints.is_empty()
+                    && clause.head.aggregate.is_none() =>
+            {
+                clause
+            }
+            _ => bail!("a query is a single predicate, like ?- edge(1, X)."),
+        };
+        let rel = q.name(clause.head.name);
+        let mut pattern = clause.head.clone();
+
+        let instance = match self.instance()? {
+            Some(instance) => instance,
+            None => bail!("unknown relation {}", rel),
+        };
+        // So that 1 finds 1.0 in a float column.
+        let id = instance.program.idents.get(rel).copied();
+        if let Some(columns) = id.and_then(|id| instance.program.columns(id)) {
+            for (idx, d) in &mut pattern.constants {
+                if let Some(coerced) = columns.get(*idx).and_then(|(_, t)| t.coerce(d)) {
+                    *d = coerced;
+                }
+            }
+        }
+        let mut rows: Vec<_> = instance
+            .contents_of(rel)?
+            .into_iter()
+            .filter(|row| {
+                row.len() == pattern.arity()
+                    && pattern.constants.iter().all(|(idx, d)| row[*idx] == *d)
+                    && {
+                        // Variables that appear more than once have to match.
+                        let mut vars: HashMap<_, &Datum> = HashMap::new();
+                        pattern
+                            .variables
+                            .iter()
+                            .all(|(idx, v)| *vars.entry(v).or_insert(&row[*idx]) == &row[*idx])
+                    }
+            })
+            .collect();
+        rows.sort();
+
+        if rows.is_empty() {
+            return Ok("no results\n".to_owned());
+        }
+        let types = instance.column_types(rel)?;
+        let mut out = String::new();
+        for row in rows {
+            out.push_str(&format!("{}({}).\n", rel, fact_args(&row, &types)));
+        }
+        Ok(out)
+    }
+}
+
+#[test]
+fn test_repl() {
+    use datadriven::walk;
+
+    walk("src/testdata/repl", |f| {
+        let mut session = Session::new();
+        f.run(|test_case| {
+            let mut out = String::new();
+            for line in test_case.input.lines() {
+                match session.handle(line) {
+                    Ok(s) => out.push_str(&s),
+                    Err(e) => out.push_str(&format!("error: {}\n", e)),
+                }
+            }
+            if test_case.directive == "builds" {
+                out.push_str(&format!("{}\n", session.builds()));
+            }
+            out
+        })
+    })
+}
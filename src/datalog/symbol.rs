@@ -0,0 +1,88 @@
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fmt,
+    hash::{Hash, Hasher},
+    sync::{Mutex, OnceLock},
+};
+
+// An interned string. Each distinct string is stored once and kept for the
+// life of the process, so a symbol is just a pointer to it: copying one is
+// free, and two are equal exactly when they point at the same place. It's a
+// pointer to a String rather than a str so that it fits in a single word.
+#[derive(Clone, Copy)]
+pub struct Symbol(&'static String);
+
+impl Symbol {
+    pub fn new(s: &str) -> Self {
+        static SYMBOLS: OnceLock<Mutex<HashMap<&'static str, &'static String>>> = OnceLock::new();
+        let mut symbols = SYMBOLS.get_or_init(Default::default).lock().unwrap();
+        match symbols.get(s) {
+            Some(interned) => Symbol(interned),
+            None => {
+                let interned: &'static String = Box::leak(Box::new(s.to_owned()));
+                symbols.insert(interned.as_str(), interned);
+                Symbol(interned)
+            }
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        self.0.as_str()
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self.0, other.0)
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::ptr::hash(self.0, state)
+    }
+}
+
+// Symbols are ordered by their text, so that output comes out the same no
+// matter which order they were interned in.
+impl PartialOrd for Symbol {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Symbol {
+    fn cmp(&self, other: &Self) -> Ordering {
+        if self == other {
+            Ordering::Equal
+        } else {
+            self.as_str().cmp(other.as_str())
+        }
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+#[test]
+fn test_symbol() {
+    let a = Symbol::new("alice");
+    let owned = String::from("alice");
+    let b = Symbol::new(&owned);
+    assert_eq!(a, b);
+    assert!(std::ptr::eq(a.as_str(), b.as_str()));
+    assert!(Symbol::new("bob") > a);
+    assert_eq!(format!("{} {:?}", a, a), "alice \"alice\"");
+}
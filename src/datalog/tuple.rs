@@ -0,0 +1,213 @@
+use std::{
+    cmp::Ordering,
+    fmt,
+    hash::{Hash, Hasher},
+    iter::FromIterator,
+    ops::{Deref, DerefMut},
+};
+
+use super::Datum;
+use crate::babyflow::Spill;
+
+// How many columns a tuple can have before it has to go on the heap.
+pub(super) const INLINE: usize = 8;
+
+// A row of a relation, or of a rule body partway through being joined.
+//
+// Every row of a relation has the same number of columns, which is known once
+// the program is compiled, and they're nearly always narrow. So rather than
+// each being its own Vec, rows up to INLINE columns wide are kept inline, and
+// joining, projecting and keying them only ever copies datums around instead
+// of allocating.
+#[derive(Clone)]
+pub(super) struct Tuple(Repr);
+
+#[derive(Clone)]
+enum Repr {
+    Inline(u8, [Datum; INLINE]),
+    Heap(Vec<Datum>),
+}
+
+// What's in the unused columns of an inline tuple.
+const EMPTY: Datum = Datum::Int(0);
+
+impl Tuple {
+    pub fn new() -> Self {
+        Tuple(Repr::Inline(0, [EMPTY; INLINE]))
+    }
+
+    pub fn push(&mut self, d: Datum) {
+        match &mut self.0 {
+            Repr::Inline(len, data) if (*len as usize) < INLINE => {
+                data[*len as usize] = d;
+                *len += 1;
+            }
+            Repr::Inline(len, data) => {
+                let mut v = data[..*len as usize].to_vec();
+                v.push(d);
+                self.0 = Repr::Heap(v);
+            }
+            Repr::Heap(v) => v.push(d),
+        }
+    }
+
+    pub fn insert(&mut self, idx: usize, d: Datum) {
+        self.push(d);
+        self[idx..].rotate_right(1);
+    }
+
+    pub fn remove(&mut self, idx: usize) -> Datum {
+        let d = self[idx];
+        self[idx..].rotate_left(1);
+        match &mut self.0 {
+            Repr::Inline(len, _) => *len -= 1,
+            Repr::Heap(v) => {
+                v.pop();
+            }
+        }
+        d
+    }
+
+    // The columns of one tuple followed by those of another.
+    pub fn concat(&self, other: &Tuple) -> Tuple {
+        if self.len() + other.len() > INLINE {
+            let mut v = Vec::with_capacity(self.len() + other.len());
+            v.extend_from_slice(self);
+            v.extend_from_slice(other);
+            return Tuple(Repr::Heap(v));
+        }
+        let mut t = self.clone();
+        for d in other.iter() {
+            t.push(*d);
+        }
+        t
+    }
+}
+
+impl Deref for Tuple {
+    type Target = [Datum];
+
+    fn deref(&self) -> &[Datum] {
+        match &self.0 {
+            Repr::Inline(len, data) => &data[..*len as usize],
+            Repr::Heap(v) => v,
+        }
+    }
+}
+
+impl DerefMut for Tuple {
+    fn deref_mut(&mut self) -> &mut [Datum] {
+        match &mut self.0 {
+            Repr::Inline(len, data) => &mut data[..*len as usize],
+            Repr::Heap(v) => v,
+        }
+    }
+}
+
+impl AsRef<[Datum]> for Tuple {
+    fn as_ref(&self) -> &[Datum] {
+        self
+    }
+}
+
+impl FromIterator<Datum> for Tuple {
+    fn from_iter<I: IntoIterator<Item = Datum>>(iter: I) -> Self {
+        let mut t = Tuple::new();
+        for d in iter {
+            t.push(d);
+        }
+        t
+    }
+}
+
+impl From<&[Datum]> for Tuple {
+    fn from(row: &[Datum]) -> Self {
+        row.iter().copied().collect()
+    }
+}
+
+// Tuples are compared by their columns, no matter how they're stored.
+impl PartialEq for Tuple {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for Tuple {}
+
+impl PartialOrd for Tuple {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Tuple {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+impl Hash for Tuple {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl Spill for Tuple {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.len().encode(buf);
+        for d in self.iter() {
+            d.encode(buf);
+        }
+    }
+
+    fn decode(buf: &mut &[u8]) -> Self {
+        let len = usize::decode(buf);
+        (0..len).map(|_| Datum::decode(buf)).collect()
+    }
+
+    fn footprint(&self) -> usize {
+        match &self.0 {
+            Repr::Inline(..) => std::mem::size_of::<Self>(),
+            Repr::Heap(v) => {
+                std::mem::size_of::<Self>() + v.capacity() * std::mem::size_of::<Datum>()
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Tuple {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[test]
+fn test_tuple() {
+    let ints = |r: std::ops::Range<i64>| r.map(Datum::Int).collect::<Tuple>();
+    let narrow = ints(0..3);
+    let wide = ints(0..INLINE as i64 + 2);
+    assert!(matches!(narrow.0, Repr::Inline(3, _)));
+    assert!(matches!(wide.0, Repr::Heap(_)));
+    assert_eq!(wide, Tuple::from(&wide[..]));
+    assert_eq!(narrow.concat(&ints(3..5)), ints(0..5));
+    assert_eq!(ints(0..4).concat(&ints(4..INLINE as i64 + 2)), wide);
+
+    let mut t = narrow.clone();
+    t.insert(1, Datum::Int(9));
+    assert_eq!(
+        &t[..],
+        &[Datum::Int(0), Datum::Int(9), Datum::Int(1), Datum::Int(2)]
+    );
+    assert_eq!(t.remove(1), Datum::Int(9));
+    assert_eq!(t, narrow);
+
+    // Atoms are a single pointer, so a datum is only two words.
+    assert_eq!(std::mem::size_of::<Datum>(), 16);
+
+    // Unused columns don't count.
+    let mut u = ints(0..4);
+    u.remove(3);
+    assert_eq!(u, narrow);
+    assert_eq!(std::collections::HashSet::from([u]).len(), 1);
+}
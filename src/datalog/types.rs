@@ -0,0 +1,426 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use anyhow::bail;
+
+use super::{Aggregate, Clause, CmpOp, Datum, Diagnostic, Ident, Program, Severity, Term, Type};
+
+// What's known about the type of an expression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Ty {
+    Unknown,
+    // An integer literal, which can be used as any kind of number.
+    Number,
+    Is(Type),
+}
+
+impl Ty {
+    fn of(d: &Datum) -> Self {
+        match d {
+            Datum::Int(_) => Ty::Number,
+            d => Ty::Is(Type::of(d)),
+        }
+    }
+
+    // The type of something that has to be both, if there is one.
+    fn unify(self, other: Ty) -> Option<Ty> {
+        match (self, other) {
+            (Ty::Unknown, t) | (t, Ty::Unknown) => Some(t),
+            (Ty::Number, Ty::Number) => Some(Ty::Number),
+            (Ty::Number, Ty::Is(t)) | (Ty::Is(t), Ty::Number) => {
+                Some(Ty::Is(t)).filter(|_| t.numeric())
+            }
+            (Ty::Is(a), Ty::Is(b)) => Some(Ty::Is(a)).filter(|_| a == b),
+        }
+    }
+}
+
+impl fmt::Display for Ty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Ty::Unknown => write!(f, "anything"),
+            Ty::Number => write!(f, "a number"),
+            Ty::Is(Type::Int) => write!(f, "an int"),
+            Ty::Is(t) => write!(f, "a {}", t),
+        }
+    }
+}
+
+impl Program {
+    // The declared columns of a relation.
+    pub(super) fn columns(&self, rel: Ident) -> Option<&[(String, Type)]> {
+        self.relations.get(&rel)?.decl.as_deref()
+    }
+
+    // The type of each of a relation's columns, as declared or as worked out
+    // from how it's used, with None for the ones that aren't known.
+    pub(super) fn column_types(&self, rel: Ident) -> Vec<Option<Type>> {
+        match self.columns(rel) {
+            Some(columns) => columns.iter().map(|(_, t)| Some(*t)).collect(),
+            None => self
+                .relations
+                .get(&rel)
+                .map_or_else(Vec::new, |rel| rel.types.clone()),
+        }
+    }
+
+    // Checks the rules and facts against the declared types of the relations
+    // they use. Relations that weren't declared can hold anything, though
+    // their columns are converted to whatever type they're used as.
+    pub(super) fn type_check(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        for rel in self.relations.values() {
+            for clause in &rel.clauses {
+                let mut errors = Vec::new();
+                self.clause_types(clause, &HashMap::new(), &mut errors);
+                for message in errors {
+                    diagnostics.push(Diagnostic {
+                        severity: Severity::Error,
+                        message,
+                        rule: Some(self.show(clause)),
+                    });
+                }
+            }
+        }
+        diagnostics
+    }
+
+    // Works out the types of a clause's variables, from the columns of the
+    // declared relations they're used in, and whatever they're equal to. The
+    // types inferred for undeclared relations are used too, but only to fill
+    // in what isn't known otherwise.
+    fn clause_types(
+        &self,
+        clause: &Clause,
+        inferred: &HashMap<Ident, Vec<Ty>>,
+        errors: &mut Vec<String>,
+    ) -> HashMap<Ident, Ty> {
+        let mut vars = HashMap::new();
+        for pred in std::iter::once(&clause.head).chain(&clause.body) {
+            let name = self.name(pred.name);
+            let columns = match self.columns(pred.name) {
+                Some(columns) => columns,
+                None => {
+                    for (idx, v) in &pred.variables {
+                        let t = inferred.get(&pred.name).and_then(|tys| tys.get(*idx));
+                        let before = vars.get(v).copied().unwrap_or(Ty::Unknown);
+                        if let Some(after) = t.and_then(|t| before.unify(*t)) {
+                            vars.insert(*v, after);
+                        }
+                    }
+                    continue;
+                }
+            };
+            if columns.len() != pred.arity() {
+                errors.push(format!(
+                    "{} is declared with {} columns, but used with {} arguments",
+                    name,
+                    columns.len(),
+                    pred.arity()
+                ));
+                continue;
+            }
+
+            for (idx, d) in &pred.constants {
+                let (col, t) = &columns[*idx];
+                if t.coerce(d).is_none() {
+                    errors.push(format!(
+                        "{} expects {} for {}, but got {}",
+                        name,
+                        Ty::Is(*t),
+                        col,
+                        d
+                    ));
+                }
+            }
+            for (idx, v) in &pred.variables {
+                self.assign(&mut vars, *v, Ty::Is(columns[*idx].1), errors);
+            }
+            if let Some((idx, agg, v)) = pred.aggregate {
+                let (col, t) = &columns[idx];
+                match agg {
+                    Aggregate::Count if *t != Type::Int => errors.push(format!(
+                        "{} expects {} for {}, but count is an int",
+                        name,
+                        Ty::Is(*t),
+                        col
+                    )),
+                    Aggregate::Count => {}
+                    Aggregate::Sum if !t.numeric() => errors.push(format!(
+                        "{} expects {} for {}, but sum is a number",
+                        name,
+                        Ty::Is(*t),
+                        col
+                    )),
+                    _ => self.assign(&mut vars, v, Ty::Is(*t), errors),
+                }
+            }
+        }
+
+        // A variable that's equal to something has its type.
+        loop {
+            let mut changed = false;
+            for c in clause.constraints.iter().filter(|c| c.op == CmpOp::Eq) {
+                for (var, other) in [(&c.lhs, &c.rhs), (&c.rhs, &c.lhs)] {
+                    if let (Term::Var(v), Ok(t)) = (var, term_ty(other, &vars)) {
+                        let before = vars.get(v).copied().unwrap_or(Ty::Unknown);
+                        match before.unify(t) {
+                            Some(after) if after != before => {
+                                vars.insert(*v, after);
+                                changed = true;
+                            }
+                            // Conflicts are reported with the constraint.
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        for c in &clause.constraints {
+            let (l, r) = match (term_ty(&c.lhs, &vars), term_ty(&c.rhs, &vars)) {
+                (Ok(l), Ok(r)) => (l, r),
+                (Err(e), _) | (_, Err(e)) => {
+                    errors.push(e);
+                    continue;
+                }
+            };
+            match l.unify(r) {
+                None => errors.push(format!("can't compare {} with {}", l, r)),
+                Some(Ty::Is(t)) if !t.numeric() && !matches!(c.op, CmpOp::Eq | CmpOp::Ne) => {
+                    errors.push(format!("{}s can't be compared with {}", t, c.op))
+                }
+                _ => {}
+            }
+        }
+        vars
+    }
+
+    fn assign(&self, vars: &mut HashMap<Ident, Ty>, v: Ident, t: Ty, errors: &mut Vec<String>) {
+        let before = vars.get(&v).copied().unwrap_or(Ty::Unknown);
+        match before.unify(t) {
+            Some(after) => {
+                vars.insert(v, after);
+            }
+            None => errors.push(format!(
+                "variable {} is used as both {} and {}",
+                self.name(v),
+                before,
+                t
+            )),
+        }
+    }
+
+    // Works out the types of the columns of the relations that weren't
+    // declared, from the facts in them and the columns of other relations
+    // they share variables with. A column holding more than one type of thing
+    // is left as Unknown.
+    fn infer_types(&self) -> HashMap<Ident, Vec<Ty>> {
+        let mut inferred: HashMap<Ident, Vec<Ty>> = HashMap::new();
+        let mut mixed = HashSet::new();
+        loop {
+            let mut next = inferred.clone();
+            let mut widen = |name: Ident, arity: usize, idx: usize, t: Ty| {
+                let tys = next.entry(name).or_insert_with(|| vec![Ty::Unknown; arity]);
+                if tys.len() != arity || mixed.contains(&(name, idx)) {
+                    return;
+                }
+                match tys[idx].unify(t) {
+                    Some(t) => tys[idx] = t,
+                    None => {
+                        mixed.insert((name, idx));
+                        tys[idx] = Ty::Unknown;
+                    }
+                }
+            };
+
+            for (name, rel) in &self.relations {
+                if rel.decl.is_some() {
+                    continue;
+                }
+                for row in &rel.facts {
+                    for (idx, d) in row.iter().enumerate() {
+                        widen(*name, row.len(), idx, Ty::of(d));
+                    }
+                }
+            }
+            for clause in self.relations.values().flat_map(|rel| &rel.clauses) {
+                let vars = self.clause_types(clause, &inferred, &mut Vec::new());
+                let var = |v: &Ident| vars.get(v).copied().unwrap_or(Ty::Unknown);
+                for pred in std::iter::once(&clause.head).chain(&clause.body) {
+                    if self.columns(pred.name).is_some() {
+                        continue;
+                    }
+                    let arity = pred.arity();
+                    for (idx, d) in &pred.constants {
+                        widen(pred.name, arity, *idx, Ty::of(d));
+                    }
+                    for (idx, v) in &pred.variables {
+                        widen(pred.name, arity, *idx, var(v));
+                    }
+                    if let Some((idx, agg, v)) = pred.aggregate {
+                        let t = match agg {
+                            Aggregate::Count => Ty::Is(Type::Int),
+                            _ => var(&v),
+                        };
+                        widen(pred.name, arity, idx, t);
+                    }
+                }
+            }
+
+            if next == inferred {
+                return inferred;
+            }
+            inferred = next;
+        }
+    }
+
+    // Turns integer literals into whatever kind of number they're used as,
+    // and converts facts to their relations' declared or inferred types. The
+    // program should have already been checked.
+    pub(super) fn coerce(&mut self) -> anyhow::Result<()> {
+        let inferred = self.infer_types();
+        let types: Vec<Vec<_>> = self
+            .relations
+            .values()
+            .map(|rel| {
+                rel.clauses
+                    .iter()
+                    .map(|clause| self.clause_types(clause, &inferred, &mut Vec::new()))
+                    .collect()
+            })
+            .collect();
+        for (name, tys) in &inferred {
+            let tys: Vec<_> = tys
+                .iter()
+                .map(|t| match t {
+                    Ty::Is(t) => Some(*t),
+                    _ => None,
+                })
+                .collect();
+            if tys.iter().any(|t| t.is_some()) {
+                self.relations.entry(*name).or_default().types = tys;
+            }
+        }
+        let columns: HashMap<Ident, Vec<Option<Type>>> = self
+            .relations
+            .iter()
+            .map(|(name, rel)| {
+                let tys = match &rel.decl {
+                    Some(decl) => decl.iter().map(|(_, t)| Some(*t)).collect(),
+                    None => rel.types.clone(),
+                };
+                (*name, tys)
+            })
+            .collect();
+
+        for (rel, types) in self.relations.values_mut().zip(types) {
+            for (clause, vars) in rel.clauses.iter_mut().zip(types) {
+                for pred in std::iter::once(&mut clause.head).chain(&mut clause.body) {
+                    let columns = columns.get(&pred.name).map_or(&[][..], |c| &c[..]);
+                    for (idx, d) in &mut pred.constants {
+                        let t = columns.get(*idx).copied().flatten();
+                        if let Some(coerced) = t.and_then(|t| t.coerce(d)) {
+                            *d = coerced;
+                        }
+                    }
+                }
+                for c in &mut clause.constraints {
+                    let l = term_ty(&c.lhs, &vars).unwrap_or(Ty::Unknown);
+                    let r = term_ty(&c.rhs, &vars).unwrap_or(Ty::Unknown);
+                    if let Some(Ty::Is(t)) = l.unify(r) {
+                        coerce_term(&mut c.lhs, t);
+                        coerce_term(&mut c.rhs, t);
+                    }
+                }
+            }
+        }
+
+        let names: Vec<_> = self.relations.keys().copied().collect();
+        for name in names {
+            let rel = self.relations.get_mut(&name).unwrap();
+            let mut facts = std::mem::take(&mut rel.facts);
+            for row in &mut facts {
+                self.coerce_row(name, row)?;
+            }
+            self.relations.get_mut(&name).unwrap().facts = facts;
+        }
+        Ok(())
+    }
+
+    // Converts a tuple to its relation's declared types, if it has them.
+    // Otherwise the values that can be are converted to the types inferred
+    // for it, and the rest are left alone.
+    pub(super) fn coerce_row(&self, rel: Ident, row: &mut [Datum]) -> anyhow::Result<()> {
+        let columns = match self.columns(rel) {
+            Some(columns) => columns,
+            None => {
+                let types = self.relations.get(&rel).map_or(&[][..], |rel| &rel.types);
+                if types.len() == row.len() {
+                    for (d, t) in row.iter_mut().zip(types) {
+                        if let Some(coerced) = t.and_then(|t| t.coerce(d)) {
+                            *d = coerced;
+                        }
+                    }
+                }
+                return Ok(());
+            }
+        };
+        if columns.len() != row.len() {
+            bail!(
+                "{} is declared with {} columns, but got {} values",
+                self.name(rel),
+                columns.len(),
+                row.len()
+            );
+        }
+        for (d, (col, t)) in row.iter_mut().zip(columns) {
+            match t.coerce(d) {
+                Some(coerced) => *d = coerced,
+                None => bail!(
+                    "{} expects {} for {}, but got {}",
+                    self.name(rel),
+                    Ty::Is(*t),
+                    col,
+                    d
+                ),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn term_ty(t: &Term, vars: &HashMap<Ident, Ty>) -> Result<Ty, String> {
+    match t {
+        Term::Var(v) => Ok(vars.get(v).copied().unwrap_or(Ty::Unknown)),
+        Term::Datum(d) => Ok(Ty::of(d)),
+        Term::Arith(_, l, r) => {
+            let (l, r) = (term_ty(l, vars)?, term_ty(r, vars)?);
+            match l.unify(r) {
+                Some(Ty::Is(t)) if !t.numeric() => Err(format!("can't do arithmetic on {}s", t)),
+                Some(t) => Ok(t),
+                None => Err(format!("can't do arithmetic on {} and {}", l, r)),
+            }
+        }
+    }
+}
+
+fn coerce_term(term: &mut Term, t: Type) {
+    match term {
+        Term::Var(_) => {}
+        Term::Datum(d) => {
+            if let Some(coerced) = t.coerce(d) {
+                *d = coerced;
+            }
+        }
+        Term::Arith(_, l, r) => {
+            coerce_term(l, t);
+            coerce_term(r, t);
+        }
+    }
+}
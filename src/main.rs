@@ -1,16 +1,297 @@
-// use std::io::{self, Read};
+use std::{
+    env, fs,
+    io::{self, BufRead, Read, Write},
+    process,
+};
 
-// pub mod babyflow;
-// mod datalog;
+use datalog::datalog::{
+    output::{self, Format},
+    repl::{Session, HELP},
+    ParseErrors, Program, Severity,
+};
 
-// fn main() -> anyhow::Result<()> {
-//     let mut buffer = String::new();
-//     io::stdin().read_to_string(&mut buffer)?;
+const USAGE: &str = "\
+usage: datalog [--output REL[,REL...]]... [--format facts|csv|json]
+               [--multiway-join] [--memory-limit SIZE] [FILE]...
+       datalog --interactive [FILE]...
+       datalog --explain [--multiway-join] [FILE]...
 
-//     let p = datalog::Program::build(&buffer);
-//     let _ = p.render("out");
+Runs the datalog program in the given files, or stdin if there are none or
+the file is -, and prints the contents of the output relations. Without
+--output, every relation is printed. If the program has .output directives,
+those relations are written to their files, and only the ones given to
+--output are printed.
 
-//     Ok(())
-// }
+With --interactive, the files are loaded and then facts, rules and queries
+are read a line at a time from stdin.
 
-fn main() {}
+With --explain, the program isn't run, and instead the order each rule's body
+would be joined in is printed.
+
+With --multiway-join, rules whose bodies are cyclic, like finding triangles,
+are joined all at once instead of a predicate at a time.
+
+With --memory-limit, each index a join looks records up in keeps at most
+about SIZE bytes in memory, like 64M or 2G, and writes the rest to temporary
+files. A join reads from two of them, and there can be many, so the limit
+isn't on the program as a whole. The rest of the program's state, such as for
+negation, aggregates and --multiway-join, is always kept in memory.";
+
+struct Args {
+    files: Vec<String>,
+    outputs: Vec<String>,
+    format: Format,
+    interactive: bool,
+    explain: bool,
+    multiway_join: bool,
+    memory_limit: Option<usize>,
+}
+
+// Returns None if we were asked for help.
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Option<Args>, String> {
+    let mut files = Vec::new();
+    let mut outputs = Vec::new();
+    let mut format = Format::Facts;
+    let mut interactive = false;
+    let mut explain = false;
+    let mut multiway_join = false;
+    let mut memory_limit = None;
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => {
+                (flag.to_owned(), Some(value.to_owned()))
+            }
+            _ => (arg.clone(), None),
+        };
+        let mut value = || {
+            inline
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("{} needs a value", flag))
+        };
+        match flag.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-o" | "--output" => outputs.extend(
+                value()?
+                    .split(',')
+                    .map(|s| s.trim().to_owned())
+                    .filter(|s| !s.is_empty()),
+            ),
+            "-f" | "--format" => format = value()?.parse().map_err(|e| format!("{}", e))?,
+            "-i" | "--interactive" => interactive = true,
+            "--explain" => explain = true,
+            "--multiway-join" => multiway_join = true,
+            "--memory-limit" => memory_limit = Some(parse_size(&value()?)?),
+            "-" => files.push(arg),
+            _ if flag.starts_with('-') => return Err(format!("unknown flag {}", flag)),
+            _ => files.push(arg),
+        }
+    }
+    if interactive && files.iter().any(|f| f == "-") {
+        return Err("can't read a file from stdin in interactive mode".to_owned());
+    }
+    if files.is_empty() && !interactive {
+        files.push("-".to_owned());
+    }
+
+    Ok(Some(Args {
+        files,
+        outputs,
+        format,
+        interactive,
+        explain,
+        multiway_join,
+        memory_limit,
+    }))
+}
+
+// Parses a number of bytes, like 512, 64K, 100M or 2G.
+fn parse_size(s: &str) -> Result<usize, String> {
+    let (digits, shift) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 10),
+        Some((i, 'm' | 'M')) => (&s[..i], 20),
+        Some((i, 'g' | 'G')) => (&s[..i], 30),
+        _ => (s, 0),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("invalid size {}", s))
+}
+
+// Reads the program, or prints everything wrong with it and returns None.
+fn load(files: &[String]) -> Option<Program> {
+    let mut p = Program::new();
+    let mut ok = true;
+    for file in files {
+        let (name, source) = if file == "-" {
+            let mut buffer = String::new();
+            match io::stdin().read_to_string(&mut buffer) {
+                Ok(_) => ("<stdin>", buffer),
+                Err(e) => {
+                    eprintln!("datalog: <stdin>: {}", e);
+                    return None;
+                }
+            }
+        } else {
+            match fs::read_to_string(file) {
+                Ok(source) => (file.as_str(), source),
+                Err(e) => {
+                    eprintln!("datalog: {}: {}", file, e);
+                    return None;
+                }
+            }
+        };
+
+        if let Err(e) = p.add(&source) {
+            ok = false;
+            match e.downcast_ref::<ParseErrors>() {
+                Some(errors) => {
+                    for e in &errors.errors {
+                        eprintln!("{}:{}", name, e);
+                    }
+                }
+                None => eprintln!("{}: {}", name, e),
+            }
+        }
+    }
+
+    if !ok {
+        return None;
+    }
+
+    for d in p.check() {
+        eprintln!("{}: {}", d.severity, d);
+        ok &= d.severity != Severity::Error;
+    }
+    if ok {
+        Some(p)
+    } else {
+        None
+    }
+}
+
+fn run(args: Args) -> anyhow::Result<bool> {
+    let mut p = match load(&args.files) {
+        Some(p) => p,
+        None => return Ok(false),
+    };
+    p.set_multiway_join(args.multiway_join);
+    p.set_memory_limit(args.memory_limit);
+    if args.explain {
+        print!("{}", p.explain()?);
+        return Ok(true);
+    }
+
+    let relations = p.relations();
+    let written = !p.outputs().is_empty();
+    let outputs = if args.outputs.is_empty() {
+        relations.clone()
+    } else {
+        if let Some(rel) = args.outputs.iter().find(|rel| !relations.contains(rel)) {
+            anyhow::bail!("unknown relation {}", rel);
+        }
+        args.outputs.clone()
+    };
+
+    // Everything comes from a single run of the program.
+    let mut results = Vec::new();
+    if let Some(rel) = relations.first() {
+        let instance = p.instantiate(rel)?;
+        let stats = instance.spill_stats();
+        if stats.records > 0 {
+            eprintln!(
+                "datalog: spilled {} records to disk, writing {} bytes in {} runs",
+                stats.records, stats.bytes, stats.runs
+            );
+        }
+        instance.write_outputs()?;
+        if written && args.outputs.is_empty() {
+            return Ok(true);
+        }
+        for rel in outputs {
+            let mut rows = instance.contents_of(&rel)?;
+            rows.sort();
+            let types = instance.column_types(&rel)?;
+            results.push((rel, types, rows));
+        }
+    }
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    output::write(&mut stdout, args.format, &results)?;
+    stdout.flush()?;
+    Ok(true)
+}
+
+// Reads lines from stdin until it's closed, after loading the given files
+// into the session.
+fn interactive(files: &[String]) -> anyhow::Result<bool> {
+    let mut session = Session::new();
+    for file in files {
+        let source = match fs::read_to_string(file) {
+            Ok(source) => source,
+            Err(e) => {
+                eprintln!("datalog: {}: {}", file, e);
+                return Ok(false);
+            }
+        };
+        if let Err(e) = session.handle(&source) {
+            eprintln!("{}: {}", file, e);
+            return Ok(false);
+        }
+    }
+
+    println!("{}", HELP);
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("> ");
+        io::stdout().flush()?;
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => break,
+        };
+        match session.handle(&line) {
+            Ok(out) => print!("{}", out),
+            Err(e) => eprintln!("error: {}", e),
+        }
+    }
+    println!();
+    Ok(true)
+}
+
+fn main() {
+    let args = match parse_args(env::args().skip(1)) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("datalog: {}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let result = if args.interactive {
+        interactive(&args.files)
+    } else {
+        run(args)
+    };
+    match result {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            // Someone downstream in the pipeline stopped reading.
+            if let Some(e) = e.downcast_ref::<io::Error>() {
+                if e.kind() == io::ErrorKind::BrokenPipe {
+                    return;
+                }
+            }
+            eprintln!("datalog: {}", e);
+            process::exit(1);
+        }
+    }
+}